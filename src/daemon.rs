@@ -0,0 +1,173 @@
+use libc;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `stop` waits for the daemon to make its final commit and exit.
+static STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pidfile used for a mount point when none is given on the command line.
+pub fn default_pidfile(mount_point: &Path) -> PathBuf {
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let mount_point = fs::canonicalize(mount_point).unwrap_or_else(|_| mount_point.to_owned());
+    let name = mount_point.to_string_lossy().trim_matches('/').replace('/', "_");
+    dir.join(format!("gitfuse-rs-{}.pid", name))
+}
+
+/// `path` relative to the current directory made absolute, a daemon runs from "/".
+pub fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_owned();
+    }
+    match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// The daemon's end of the pipe the process that started it waits on, it
+/// exits with success once the daemon reports the mount is up.
+pub struct Ready {
+    pipe: File,
+}
+impl Ready {
+    pub fn ok(mut self) {
+        let _ = self.pipe.write_all(READY);
+    }
+    /// Makes the waiting process print `message` and exit with an error.
+    pub fn fail(mut self, message: &str) {
+        let _ = self.pipe.write_all(message.as_bytes());
+    }
+}
+
+/// What the daemon writes to the pipe when it started, anything else is an error message.
+static READY: &[u8] = b"ready";
+
+/// Waits in the process that started the daemon for it to report whether it
+/// started, and exits with the outcome. The pipe closing without a report is a failure.
+fn wait_for_daemon(mut pipe: File) -> ! {
+    let mut report = Vec::new();
+    let _ = pipe.read_to_end(&mut report);
+    if report == READY {
+        process::exit(0);
+    }
+    if report.is_empty() {
+        error!("The daemon exited before mounting");
+    } else {
+        error!("{}", String::from_utf8_lossy(&report));
+    }
+    process::exit(1)
+}
+
+/// Detaches from the terminal, leaving the calling process running in the
+/// background with stdout and stderr redirected to `log`, or discarded. The
+/// process that called it exits once the daemon reports through the returned `Ready`.
+pub fn daemonize(log: Option<&Path>) -> io::Result<Ready> {
+    let log = match log {
+        Some(path) => OpenOptions::new().append(true).create(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    //Children like fusermount and the hooks must not keep the pipe open, or the
+    //waiting process would never see it close if the daemon dies
+    for end in &[&read, &write] {
+        if unsafe { libc::fcntl(end.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => drop(read),
+            _ => {
+                drop(write);
+                wait_for_daemon(read);
+            }
+        }
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        //Fork again so the daemon can never get a controlling terminal back
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => process::exit(0),
+        }
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    env::set_current_dir("/")?;
+    Ok(Ready { pipe: write })
+}
+
+/// Writes the process id to `path` and locks it for as long as the returned
+/// file is open, the lock tells `stop` the process is still the daemon.
+pub fn write_pidfile(path: &Path) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).create(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "another daemon holds it"));
+        }
+        return Err(e);
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", process::id())?;
+    Ok(file)
+}
+
+/// True if a process holds the lock on the pidfile `file`.
+fn locked(file: &File) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+        return Ok(false);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(true)
+    } else {
+        Err(e)
+    }
+}
+
+/// Asks the daemon owning `pidfile` to shut down and waits for it to exit. The pid
+/// is only trusted while the pidfile is locked, a stale one may name another process.
+pub fn stop(pidfile: &Path) -> io::Result<()> {
+    let mut file = File::open(pidfile)?;
+    if !locked(&file)? {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no daemon is running"));
+    }
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let pid: libc::pid_t = match content.trim().parse() {
+        Ok(pid) => pid,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid pidfile")),
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let start = Instant::now();
+    while locked(&file)? {
+        if start.elapsed() > STOP_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "daemon did not exit"));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use fuse::FUSE_ROOT_ID;
+use git2::Oid;
+use std::ffi::OsStr;
+
+/// Read-only file at the root of the mount describing the state of the filesystem.
+pub static STATUS_NAME: &str = ".gitfs-status";
+/// Inode reserved for the status file, right after the two root placeholders.
+pub static STATUS_INO: u64 = 2;
+/// Read-only file at the root of the mount with the operation metrics.
+pub static STATS_NAME: &str = ".gitfs-stats";
+pub static STATS_INO: u64 = 3;
+
+/// Inode of the control file called `name` in the root of the mount.
+pub fn lookup(name: &OsStr) -> Option<u64> {
+    if name == STATUS_NAME {
+        Some(STATUS_INO)
+    } else if name == STATS_NAME {
+        Some(STATS_INO)
+    } else {
+        None
+    }
+}
+
+/// True if `name` in the directory `parent` is taken by a control file, so no
+/// entry of that name may be created there.
+pub fn shadows(parent: u64, name: &OsStr) -> bool {
+    parent == FUSE_ROOT_ID && lookup(name).is_some()
+}
+
+pub fn is_control(ino: u64) -> bool {
+    ino == STATUS_INO || ino == STATS_INO
+}
+
+pub enum PushState {
+    Pending,
+    Pushed(Oid),
+    Rejected(String),
+    Failed(String),
+}
+
+pub struct Status {
+    pub last_commit: Option<Oid>,
+    pub push: Option<PushState>,
+    /// Why the hooks refused the last commit attempt.
+    pub hook_failure: Option<String>,
+    /// Files kept out of the last commit because they look like they hold secrets.
+    pub held: Vec<(String, String)>,
+    /// Object count and size of the repository, as of the last statfs.
+    pub objects: Option<(u64, u64)>,
+}
+impl Status {
+    pub fn new() -> Self {
+        Status {
+            last_commit: None,
+            push: None,
+            hook_failure: None,
+            held: Vec::new(),
+            objects: None,
+        }
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        match self.last_commit {
+            Some(oid) => out += &format!("commit: {}\n", oid),
+            None => out += "commit: none\n",
+        }
+        match self.push {
+            Some(PushState::Pending) => out += "push: pending\n",
+            Some(PushState::Pushed(oid)) => out += &format!("push: ok {}\n", oid),
+            Some(PushState::Rejected(ref e)) => out += &format!("push: rejected {}\n", e),
+            Some(PushState::Failed(ref e)) => out += &format!("push: failed {}\n", e),
+            None => {}
+        }
+        if let Some(ref e) = self.hook_failure {
+            let lines: Vec<&str> = e.lines().map(str::trim).collect();
+            out += &format!("hooks: rejected {}\n", lines.join(" "));
+        }
+        if let Some((count, bytes)) = self.objects {
+            out += &format!("objects: {} {} bytes\n", count, bytes);
+        }
+        for &(ref path, ref finding) in &self.held {
+            out += &format!("held: {} {}\n", path, finding);
+        }
+        out
+    }
+}
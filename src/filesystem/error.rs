@@ -0,0 +1,65 @@
+use filesystem::error_codes;
+use git2::{self, ErrorCode};
+use std::fmt;
+use std::io;
+use std::os::raw::c_int;
+use std::result;
+
+/// What can go wrong loading the tree and committing it, kept so it can both be
+/// shown to the user and turned into an errno for a FUSE reply. The FUSE
+/// operations themselves work with plain errnos.
+#[derive(Debug)]
+pub enum Error {
+    Git(git2::Error),
+    Io(io::Error),
+    /// A hook or rule refused the change, retrying will not help.
+    Rejected(String),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    pub fn errno(&self) -> c_int {
+        match *self {
+            Error::Git(ref e) => match e.code() {
+                ErrorCode::NotFound => error_codes::ENOENT,
+                ErrorCode::Exists => error_codes::EEXIST,
+                ErrorCode::Locked => error_codes::EAGAIN,
+                _ => error_codes::EIO,
+            },
+            Error::Io(ref e) => e.raw_os_error().unwrap_or(error_codes::EIO),
+            Error::Rejected(_) => error_codes::EPERM,
+        }
+    }
+    /// True if trying the same thing again later might work.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            Error::Git(ref e) => e.code() == ErrorCode::Locked || e.code() == ErrorCode::Modified,
+            Error::Io(ref e) => match e.kind() {
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+                _ => false,
+            },
+            Error::Rejected(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Git(ref e) => write!(f, "{}", e.message()),
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Rejected(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<git2::Error> for Error {
+    fn from(e: git2::Error) -> Self {
+        Error::Git(e)
+    }
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,9 +1,18 @@
 use filesystem::error_codes;
-use git2::{Oid, Repository, Tree, TreeEntry};
+use filesystem::scratch::Scratch;
+use git2::{self, ObjectType, Oid, Repository, Tree, TreeEntry};
+use std::collections::HashMap;
+use std::io;
 use std::os::raw::c_int;
 use std::str::Split;
 
 use fuse::FileType;
+
+/// What the inode table holds for a removed entry, no path can contain a NUL
+/// so it never resolves to a later entry. Slots are not reused, the kernel
+/// may still know the inode.
+pub static REMOVED: &str = "\0";
+
 #[derive(PartialEq)]
 #[derive(Debug)]
 pub struct FilesystemEntry {
@@ -19,6 +28,21 @@ pub struct FilesystemEntry {
     pub content: Option<Vec<u8>>,
     pub write: bool,
     pub write_mode: u32,
+    //Open write handles, the buffer is kept until the last of them is released
+    pub writers: u32,
+
+    //Local only, kept out of the committed tree
+    pub local: bool,
+    //Content is in the scratch directory instead of `content`
+    pub spilled: bool,
+    //Hard link group this file shares its content with
+    pub link: Option<usize>,
+}
+fn spilled_to(scratch: Option<&Scratch>) -> io::Result<&Scratch> {
+    match scratch {
+        Some(s) => Ok(s),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "no scratch directory")),
+    }
 }
 struct GitEntry {
     pub oid: Oid,
@@ -27,7 +51,11 @@ struct GitEntry {
 }
 impl FilesystemEntry {
     pub fn new(file_type: FileType, name: String, path: String, inodes: &mut Vec<String>, file_mode : i32) -> Self {
-        inodes.push(path + "/" + name.as_str());
+        if path.is_empty() {
+            inodes.push(name.clone());
+        } else {
+            inodes.push(path + "/" + name.as_str());
+        }
         Self {
             name,
             file_type,
@@ -40,8 +68,12 @@ impl FilesystemEntry {
                 _ => None,
             },
             write: false,
+            writers: 0,
             write_mode: 0,
             file_mode,
+            local: false,
+            spilled: false,
+            link: None,
         }
     }
     pub fn add(&mut self, file: FilesystemEntry) -> Option<&FilesystemEntry> {
@@ -69,13 +101,19 @@ impl FilesystemEntry {
             None => return Err(error_codes::ENOENT),
         };
         if self.children[index].file_type == file_type {
-            inodes.remove(self.children[index].ino);
-            self.children.remove(index);
+            self.children.remove(index).forget(inodes);
             return Ok(());
         } else {
             return Err(error_codes::ENOTDIR);
         }
     }
+    /// Marks the inodes of this entry and everything below it as removed.
+    fn forget(&self, inodes: &mut Vec<String>) {
+        for child in &self.children {
+            child.forget(inodes);
+        }
+        inodes[self.ino] = REMOVED.to_owned();
+    }
     pub fn get_path(&self, path: &str) -> Option<&FilesystemEntry> {
         let mut path = path.to_owned();
         if path.is_empty() {
@@ -124,6 +162,261 @@ impl FilesystemEntry {
         };
         Some(ret)
     }
+    /// True if this entry or any entry below it is open for writing.
+    pub fn is_writing(&self) -> bool {
+        self.write || self.children.iter().any(|c| c.is_writing())
+    }
+    /// Replaces the dirty content of this file, spilling it to `scratch` if there is one.
+    pub fn load(&mut self, data: &[u8], scratch: Option<&Scratch>, path: &str) -> io::Result<()> {
+        match scratch {
+            Some(scratch) => {
+                scratch.store(path, data)?;
+                self.content = None;
+                self.spilled = true;
+            }
+            None => {
+                self.content = Some(data.to_owned());
+                self.spilled = false;
+            }
+        }
+        Ok(())
+    }
+    /// Gives this file an empty buffer if it has none, local files only live in their buffer.
+    pub fn ensure_buffer(&mut self, scratch: Option<&Scratch>, path: &str) -> io::Result<()> {
+        if self.spilled || self.content.is_some() {
+            return Ok(());
+        }
+        self.load(&[], scratch, path)
+    }
+    /// Writes `data` over the dirty content at `offset`.
+    pub fn write_at(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+        scratch: Option<&Scratch>,
+        path: &str,
+    ) -> io::Result<()> {
+        if self.spilled {
+            return spilled_to(scratch)?.write_at(path, offset as u64, data);
+        }
+        let content = match self.content {
+            Some(ref mut c) => c,
+            None => return Err(io::Error::from_raw_os_error(error_codes::EIO)),
+        };
+        //Overwrite in place, a write past the end leaves a zero-filled gap
+        let end = offset + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(data);
+        Ok(())
+    }
+    /// Cuts the dirty content off at `len`, or zero-fills it up to `len`.
+    pub fn truncate(&mut self, len: u64, scratch: Option<&Scratch>, path: &str) -> io::Result<()> {
+        if self.spilled {
+            return spilled_to(scratch)?.truncate(path, len);
+        }
+        match self.content {
+            Some(ref mut c) => {
+                c.resize(len as usize, 0);
+                Ok(())
+            }
+            None => Err(io::Error::from_raw_os_error(error_codes::EIO)),
+        }
+    }
+    /// Writes the dirty content of this file to `repo` as a blob, or only hashes it
+    /// when there is no `repo`. Spilled content is streamed from the scratch file.
+    pub fn store(&self, repo: Option<&Repository>, scratch: Option<&Scratch>, path: &str) -> Result<Oid, git2::Error> {
+        if self.spilled {
+            let file = match scratch {
+                Some(s) => s.path(path),
+                None => return Err(git2::Error::from_str("no scratch directory")),
+            };
+            return match repo {
+                Some(repo) => repo.blob_path(&file),
+                None => Oid::hash_file(ObjectType::Blob, &file),
+            };
+        }
+        let content = match self.content {
+            Some(ref c) => c,
+            None => return Err(git2::Error::from_str("no content")),
+        };
+        match repo {
+            Some(repo) => repo.blob(content),
+            None => Oid::hash_object(ObjectType::Blob, content),
+        }
+    }
+    pub fn buffer_at(
+        &self,
+        offset: u64,
+        size: u32,
+        scratch: Option<&Scratch>,
+        path: &str,
+    ) -> io::Result<Vec<u8>> {
+        if self.spilled {
+            return spilled_to(scratch)?.read_at(path, offset, size);
+        }
+        match self.content {
+            Some(ref c) => {
+                let start = (offset as usize).min(c.len());
+                let end = (start + size as usize).min(c.len());
+                Ok(c[start..end].to_owned())
+            }
+            None => Err(io::Error::from_raw_os_error(error_codes::EISDIR)),
+        }
+    }
+    pub fn buffer_len(&self, scratch: Option<&Scratch>, path: &str) -> io::Result<u64> {
+        if self.spilled {
+            return spilled_to(scratch)?.len(path);
+        }
+        Ok(self.content.as_ref().map_or(0, |c| c.len() as u64))
+    }
+    /// Drops the dirty content once it has been written to the repository.
+    pub fn clean(&mut self, scratch: Option<&Scratch>, path: &str) -> io::Result<()> {
+        if self.spilled {
+            spilled_to(scratch)?.remove(path)?;
+            self.spilled = false;
+        }
+        self.content = Some(Vec::new());
+        Ok(())
+    }
+    /// Removes the entries below this entry that are not in its commit, local ones and
+    /// directories that would be left out of it, returning them with the path of their parent.
+    pub fn take_uncommitted(&mut self, path: &str, placeholder: Option<&str>, taken: &mut Vec<(String, FilesystemEntry)>) {
+        let mut i = 0;
+        while i < self.children.len() {
+            let mut child_path = path.to_owned();
+            if !child_path.is_empty() {
+                child_path += "/";
+            }
+            child_path += self.children[i].name.as_str();
+            if self.children[i].local {
+                taken.push((path.to_owned(), self.children.remove(i)));
+                continue;
+            }
+            if self.children[i].file_type == FileType::Directory {
+                let uncommitted = match self.children[i].build(None, child_path.as_str(), &HashMap::new(), placeholder) {
+                    Ok(oid) => oid.is_none(),
+                    Err(_) => false,
+                };
+                if uncommitted {
+                    taken.push((path.to_owned(), self.children.remove(i)));
+                    continue;
+                }
+                self.children[i].take_uncommitted(child_path.as_str(), placeholder, taken);
+            }
+            i += 1;
+        }
+    }
+    /// Adds `entry` taken from another tree, a directory that is here already gets
+    /// the children of `entry` instead. Files that are here already are dropped.
+    pub fn carry(&mut self, entry: FilesystemEntry) {
+        if entry.file_type == FileType::Directory {
+            if let Some(dir) = self.index_mut(entry.name.as_str()) {
+                if dir.file_type == FileType::Directory {
+                    for child in entry.children {
+                        dir.carry(child);
+                    }
+                    return;
+                }
+            }
+        }
+        self.add(entry);
+    }
+    /// Collects the paths of the committed files below this entry that are open for writing.
+    pub fn writing(&self, path: &str, found: &mut Vec<String>) {
+        for child in &self.children {
+            if child.local {
+                continue;
+            }
+            let mut child_path = path.to_owned();
+            if !child_path.is_empty() {
+                child_path += "/";
+            }
+            child_path += child.name.as_str();
+            if child.write {
+                found.push(child_path.clone());
+            }
+            child.writing(child_path.as_str(), found);
+        }
+    }
+    /// Collects the path and inode of this entry and every entry below it.
+    pub fn inodes(&self, path: &str, found: &mut HashMap<String, usize>) {
+        for child in &self.children {
+            let mut child_path = path.to_owned();
+            if !child_path.is_empty() {
+                child_path += "/";
+            }
+            child_path += child.name.as_str();
+            child.inodes(child_path.as_str(), found);
+        }
+        found.insert(path.to_owned(), self.ino);
+    }
+    /// Moves this entry and everything below it into the inode table `inodes`,
+    /// paths in `known` keep their inode and the rest get new ones.
+    pub fn remap(&mut self, path: &str, known: &HashMap<String, usize>, inodes: &mut Vec<String>) {
+        for child in self.children.iter_mut() {
+            let mut child_path = path.to_owned();
+            if !child_path.is_empty() {
+                child_path += "/";
+            }
+            child_path += child.name.as_str();
+            child.remap(child_path.as_str(), known, inodes);
+        }
+        self.ino = match known.get(path) {
+            Some(&ino) => ino,
+            None => {
+                inodes.push(path.to_owned());
+                inodes.len() - 1
+            }
+        };
+    }
+    /// Collects the directory and blob of every committed .gitignore below this entry.
+    pub fn gitignores(&self, path: &str, found: &mut Vec<(String, Oid)>) {
+        for child in &self.children {
+            if child.local {
+                continue;
+            }
+            match child.file_type {
+                FileType::RegularFile if child.name == ".gitignore" => {
+                    if let Some(oid) = child.oid {
+                        found.push((path.to_owned(), oid));
+                    }
+                }
+                FileType::Directory => {
+                    let mut child_path = path.to_owned();
+                    if !child_path.is_empty() {
+                        child_path += "/";
+                    }
+                    child_path += child.name.as_str();
+                    child.gitignores(child_path.as_str(), found);
+                }
+                _ => {}
+            }
+        }
+    }
+    /// Collects the path and blob of every committed file below this entry.
+    pub fn blobs(&self, path: &str, found: &mut Vec<(String, Oid)>) {
+        for child in &self.children {
+            if child.local {
+                continue;
+            }
+            let mut child_path = path.to_owned();
+            if !child_path.is_empty() {
+                child_path += "/";
+            }
+            child_path += child.name.as_str();
+            match child.file_type {
+                FileType::RegularFile => {
+                    if let Some(oid) = child.oid {
+                        found.push((child_path, oid));
+                    }
+                }
+                FileType::Directory => child.blobs(child_path.as_str(), found),
+                _ => {}
+            }
+        }
+    }
     pub fn index(&self, index: &str) -> Option<&FilesystemEntry> {
         for child in self.children.iter() {
             if child.name == index {
@@ -147,7 +440,7 @@ impl FilesystemEntry {
         mut path: String,
         inodes: &mut Vec<String>,
         file_mode: i32,
-    ) -> FilesystemEntry {
+    ) -> Result<FilesystemEntry, git2::Error> {
         let mut children = Vec::new();
         if !path.is_empty() {
             path = path + "/";
@@ -158,11 +451,11 @@ impl FilesystemEntry {
                 repo,
                 path.clone() + name.as_str(),
                 inodes,
-            ));
+            )?);
         }
 
         inodes.push(path.clone() + name.as_str());
-        Self {
+        Ok(Self {
             name,
             file_type: FileType::Directory,
             oid: Some(tree.id()),
@@ -171,19 +464,26 @@ impl FilesystemEntry {
             size: 0u64,
             content: None,
             write: false,
+            writers: 0,
             write_mode: 0,
             file_mode,
-        }
+            local: false,
+            spilled: false,
+            link: None,
+        })
     }
     pub fn from_tree_entry(
         treeEntry: &TreeEntry,
         repo: &Repository,
         path: String,
         inodes: &mut Vec<String>,
-    ) -> FilesystemEntry {
-        let name: String = treeEntry.name().unwrap().to_owned();
+    ) -> Result<FilesystemEntry, git2::Error> {
+        let name: String = match treeEntry.name() {
+            Some(name) => name.to_owned(),
+            None => return Err(git2::Error::from_str("file name is not valid utf-8")),
+        };
         let file_mode = treeEntry.filemode();
-        let treeEntry = treeEntry.to_object(repo).unwrap();
+        let treeEntry = treeEntry.to_object(repo)?;
         let mut full_path = path.clone();
         if !full_path.is_empty() {
             full_path = full_path + "/";
@@ -196,7 +496,7 @@ impl FilesystemEntry {
             Ok(f) => {
                 let size = f.content().len() as u64;
                 inodes.push(full_path);
-                return FilesystemEntry {
+                return Ok(FilesystemEntry {
                     name,
                     file_type: FileType::RegularFile,
                     oid: Some(oid),
@@ -205,11 +505,15 @@ impl FilesystemEntry {
                     size,
                     content: Some(Vec::new()),
                     write: false,
+                    writers: 0,
                     write_mode: 0,
                     file_mode,
-                };
+                    local: false,
+                    spilled: false,
+                    link: None,
+                });
             }
-            Err(e) => {}
+            Err(_) => {}
         };
         match treeEntry.as_tree() {
             Some(t) => FilesystemEntry::from_tree(t, repo, name, path, inodes, file_mode),
@@ -217,7 +521,7 @@ impl FilesystemEntry {
             //empty tree?
             {
                 inodes.push(full_path);
-                FilesystemEntry {
+                Ok(FilesystemEntry {
                     name,
                     file_type: FileType::Directory,
                     oid: Some(oid),
@@ -226,23 +530,72 @@ impl FilesystemEntry {
                     size: 0,
                     content: None,
                     write: false,
+                    writers: 0,
                     write_mode: 0,
                     file_mode,
-                }
+                    local: false,
+                    spilled: false,
+                    link: None,
+                })
             }
         }
     }
-    pub fn to_git_object(&self, repo: &mut Repository) -> Option<Oid> {
+    /// Writes this entry to the repository as a tree or returns its blob, the files at the
+    /// paths in `held` get the blob they map to instead of their own, or are left out if it
+    /// is None. Empty directories are left out unless a `placeholder` file name is given to keep them with.
+    pub fn to_git_object_holding(
+        &self,
+        repo: &mut Repository,
+        path: &str,
+        held: &HashMap<String, Option<Oid>>,
+        placeholder: Option<&str>,
+    ) -> Result<Option<Oid>, git2::Error> {
+        self.build(Some(repo), path, held, placeholder)
+    }
+    /// The id `to_git_object_holding` would give this entry, without writing anything to the repository.
+    pub fn tree_id(&self, placeholder: Option<&str>) -> Result<Option<Oid>, git2::Error> {
+        self.build(None, "", &HashMap::new(), placeholder)
+    }
+    /// Builds the git object of this entry, trees are only hashed when there is no `repo` to write them to.
+    fn build(
+        &self,
+        repo: Option<&Repository>,
+        path: &str,
+        held: &HashMap<String, Option<Oid>>,
+        placeholder: Option<&str>,
+    ) -> Result<Option<Oid>, git2::Error> {
         match self.file_type {
             FileType::RegularFile => {
-                return self.oid;
+                if let Some(oid) = held.get(path) {
+                    return Ok(*oid);
+                }
+                return Ok(self.oid);
             }
             FileType::Directory => {
                 let mut entries = Vec::new();
+                //Children left out because they are local or held, or directories of only those
+                let mut hidden = false;
                 for child in &self.children {
-                    let oid = match child.to_git_object(repo) {
+                    if child.local {
+                        hidden = true;
+                        continue;
+                    }
+                    let mut child_path = path.to_owned();
+                    if !child_path.is_empty() {
+                        child_path += "/";
+                    }
+                    child_path += child.name.as_str();
+                    let oid = match child.build(repo, child_path.as_str(), held, placeholder)? {
                         Some(oid) => oid,
-                        None => continue,
+                        None => {
+                            //With a placeholder only a directory hiding something is left out
+                            if held.contains_key(&child_path)
+                                || (placeholder.is_some() && child.file_type == FileType::Directory)
+                            {
+                                hidden = true;
+                            }
+                            continue;
+                        }
                     };
                     let name = child.name.clone();
                     let file_mode = child.file_mode.clone();
@@ -252,31 +605,53 @@ impl FilesystemEntry {
                         file_mode,
                     });
                 }
-                if entries.is_empty() {
-                    let oid = match repo.blob(&[0u8,0]) {
-                        Ok(oid) => oid,
-                        Err(e) => panic!(e),
+                //The root is always a tree, even an empty one. A placeholder in a directory
+                //that only looks empty would give away what is kept out of it
+                if entries.is_empty() && !path.is_empty() {
+                    let name = match placeholder {
+                        Some(name) if !hidden => name,
+                        _ => return Ok(None),
                     };
-                    println!("{:?}",oid);
-                    entries.push( GitEntry{
-                       name : ".gitfs".to_owned(),
-                        oid : oid,
-                        file_mode : 0o100000,
+                    let oid = match repo {
+                        Some(repo) => repo.blob(&[])?,
+                        None => Oid::hash_object(ObjectType::Blob, &[])?,
+                    };
+                    entries.push(GitEntry {
+                        name: name.to_owned(),
+                        oid,
+                        file_mode: 0o100644,
                     });
                 }
-                match repo.treebuilder(None) {
-                    Ok(mut tb) => {
-                        for entry in entries {
-                            tb.insert(entry.name, entry.oid, entry.file_mode);
-                        }
-                        Some(tb.write().unwrap())
-                    }
-                    Err(e) => {
-                        panic!(e);
-                    }
+                let repo = match repo {
+                    Some(repo) => repo,
+                    None => return Ok(Some(hash_tree(entries)?)),
+                };
+                let mut tb = repo.treebuilder(None)?;
+                for entry in entries {
+                    tb.insert(entry.name, entry.oid, entry.file_mode)?;
                 }
+                Ok(Some(tb.write()?))
             }
-            _ => None,
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The id of the tree holding `entries`, serialized the way git writes trees.
+fn hash_tree(mut entries: Vec<GitEntry>) -> Result<Oid, git2::Error> {
+    //Git sorts directories as if their name ended with a slash
+    fn sort_key(entry: &GitEntry) -> Vec<u8> {
+        let mut key = entry.name.as_bytes().to_owned();
+        if entry.file_mode & 0o170000 == 0o040000 {
+            key.push(b'/');
         }
+        key
+    }
+    entries.sort_by_key(sort_key);
+    let mut data = Vec::new();
+    for entry in entries {
+        data.extend_from_slice(format!("{:o} {}\0", entry.file_mode, entry.name).as_bytes());
+        data.extend_from_slice(entry.oid.as_bytes());
     }
+    Oid::hash_object(ObjectType::Tree, &data)
 }
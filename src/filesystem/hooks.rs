@@ -0,0 +1,124 @@
+use git2::build::CheckoutBuilder;
+use git2::{Index, Oid, Repository};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
+
+/// Checkout the hooks run in, kept in a directory with an unguessable name
+/// under the git dir and updated from one commit to the next.
+pub struct Checkout {
+    dir: TempDir,
+    /// Handle on the repository with the checkout as its work tree and an index of its own.
+    repository: Repository,
+    hooks: PathBuf,
+}
+impl Checkout {
+    pub fn new(repository: &Repository) -> Result<Checkout, String> {
+        let dir = TempDir::new_in(repository.path(), "gitfs-hooks")
+            .map_err(|e| format!("failed to create hook checkout: {}", e))?;
+        let work_tree = dir.path().join("tree");
+        fs::create_dir(&work_tree).map_err(|e| format!("failed to create hook checkout: {}", e))?;
+        let handle = Repository::open(repository.path())
+            .and_then(|handle| handle.set_workdir(&work_tree, false).map(|_| handle))
+            .map_err(|e| format!("failed to open repository for hooks: {}", e))?;
+        let mut index = Index::open(&dir.path().join("index"))
+            .map_err(|e| format!("failed to create hook index: {}", e))?;
+        handle.set_index(&mut index);
+        Ok(Checkout {
+            dir,
+            repository: handle,
+            hooks: hooks_dir(repository),
+        })
+    }
+
+    /// Runs the repository's pre-commit and commit-msg hooks against `tree`. Returns
+    /// the message to commit with, as the commit-msg hook may rewrite it, or why
+    /// the commit was refused.
+    pub fn run(&self, tree: Oid, message: &str) -> Result<String, String> {
+        let work_tree = self.dir.path().join("tree");
+        let index_path = self.dir.path().join("index");
+        let message_path = self.dir.path().join("COMMIT_EDITMSG");
+
+        //Only files that differ from the last checkout are written
+        let tree = self
+            .repository
+            .find_tree(tree)
+            .map_err(|e| format!("failed to find tree for hooks: {}", e))?;
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().remove_untracked(true);
+        self.repository
+            .checkout_tree(tree.as_object(), Some(&mut checkout))
+            .map_err(|e| format!("failed to check out tree for hooks: {}", e))?;
+        self.repository
+            .index()
+            .and_then(|mut index| index.read_tree(&tree).and_then(|_| index.write()))
+            .map_err(|e| format!("failed to write hook index: {}", e))?;
+
+        let hook = Hook {
+            repository: &self.repository,
+            dir: &self.hooks,
+            work_tree: &work_tree,
+            index: &index_path,
+        };
+        hook.run("pre-commit", None)?;
+
+        File::create(&message_path)
+            .and_then(|mut file| writeln!(file, "{}", message))
+            .map_err(|e| format!("failed to write commit message: {}", e))?;
+        hook.run("commit-msg", Some(&message_path))?;
+        let mut message = String::new();
+        File::open(&message_path)
+            .and_then(|mut file| file.read_to_string(&mut message))
+            .map_err(|e| format!("failed to read commit message: {}", e))?;
+        Ok(message.trim_end().to_owned())
+    }
+}
+
+/// Directory hooks are looked up in, `core.hooksPath` if it is set.
+fn hooks_dir(repository: &Repository) -> PathBuf {
+    let configured = repository
+        .config()
+        .and_then(|config| config.get_path("core.hooksPath"));
+    match configured {
+        Ok(ref path) if path.is_absolute() => path.clone(),
+        Ok(path) => repository.workdir().unwrap_or_else(|| repository.path()).join(path),
+        Err(_) => repository.path().join("hooks"),
+    }
+}
+
+struct Hook<'a> {
+    repository: &'a Repository,
+    dir: &'a Path,
+    work_tree: &'a Path,
+    index: &'a Path,
+}
+impl<'a> Hook<'a> {
+    /// Runs the hook called `name` if it exists and is executable, like git does.
+    fn run(&self, name: &str, arg: Option<&Path>) -> Result<(), String> {
+        let path = self.dir.join(name);
+        match fs::metadata(&path) {
+            Ok(ref meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => {}
+            _ => return Ok(()),
+        }
+        let mut command = Command::new(&path);
+        if let Some(arg) = arg {
+            command.arg(arg);
+        }
+        let output = command
+            .current_dir(self.work_tree)
+            .env("GIT_DIR", self.repository.path())
+            .env("GIT_WORK_TREE", self.work_tree)
+            .env("GIT_INDEX_FILE", self.index)
+            .output()
+            .map_err(|e| format!("failed to run {} hook: {}", name, e))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let mut out = String::from_utf8_lossy(&output.stderr).into_owned();
+        out += &String::from_utf8_lossy(&output.stdout);
+        Err(format!("{} hook {}: {}", name, output.status, out.trim()))
+    }
+}
@@ -0,0 +1,191 @@
+use glob::{MatchOptions, Pattern};
+
+/// Rewrites the rules of a .gitignore found in `dir` so they can be added to
+/// the repository as root level rules that only match below `dir`.
+pub fn scoped_rules(dir: &str, content: &str) -> String {
+    let mut rules = String::new();
+    for line in content.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if dir.is_empty() {
+            rules += line;
+            rules += "\n";
+            continue;
+        }
+        let (negate, pattern) = if line.starts_with('!') {
+            ("!", &line[1..])
+        } else {
+            ("", line)
+        };
+        //A slash anywhere but the end anchors the pattern to the directory of the .gitignore
+        let anchored = pattern.trim_end_matches('/').contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if anchored {
+            rules += &format!("{}/{}/{}\n", negate, dir, pattern);
+        } else {
+            rules += &format!("{}/{}/**/{}\n", negate, dir, pattern);
+        }
+    }
+    rules
+}
+
+
+/// One rule of a .gitignore.
+struct Rule {
+    pattern: Pattern,
+    /// The rule started with "!", a match takes the path back in.
+    negate: bool,
+    /// The rule ended with "/", only directories match it.
+    dir_only: bool,
+    /// The rule has a slash before its end, it matches the whole path instead of the name.
+    anchored: bool,
+}
+
+/// The rules of the .gitignore files in the mounted tree. Only these count,
+/// the .gitignore files and info/exclude of a work tree on disk do not.
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+impl Rules {
+    pub fn new() -> Self {
+        Rules { rules: Vec::new() }
+    }
+    /// Adds rules as `scoped_rules` returns them. Invalid patterns are skipped
+    /// and the first of them is returned as the error.
+    pub fn add(&mut self, rules: &str) -> Result<(), String> {
+        let mut invalid = None;
+        for line in rules.lines() {
+            let (negate, line) = if line.starts_with('!') {
+                (true, &line[1..])
+            } else {
+                (false, line)
+            };
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            match Pattern::new(line.trim_start_matches('/')) {
+                Ok(pattern) => self.rules.push(Rule {
+                    pattern,
+                    negate,
+                    dir_only,
+                    anchored,
+                }),
+                Err(e) => {
+                    if invalid.is_none() {
+                        invalid = Some(format!("{}: {}", line, e));
+                    }
+                }
+            }
+        }
+        match invalid {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+    /// True if `path` is ignored, directories are given with a trailing slash.
+    /// Like git, everything inside an ignored directory is ignored with it.
+    pub fn matches(&self, path: &str) -> bool {
+        let is_dir = path.ends_with('/');
+        let path = path.trim_end_matches('/');
+        if path.is_empty() {
+            return false;
+        }
+        let mut end = 0;
+        while let Some(i) = path[end..].find('/') {
+            end += i;
+            if self.matches_entry(&path[..end], true) {
+                return true;
+            }
+            end += 1;
+        }
+        self.matches_entry(path, is_dir)
+    }
+    /// The last rule that matches decides, as in a .gitignore.
+    fn matches_entry(&self, path: &str, is_dir: bool) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let name = match path.rfind('/') {
+            Some(i) => &path[i + 1..],
+            None => path,
+        };
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let subject = if rule.anchored { path } else { name };
+            if rule.pattern.matches_with(subject, &options) {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scoped_rules, Rules};
+
+    fn rules(dir: &str, content: &str) -> Rules {
+        let mut rules = Rules::new();
+        rules.add(&scoped_rules(dir, content)).unwrap();
+        rules
+    }
+
+    #[test]
+    fn root_rules_are_kept_as_they_are() {
+        assert_eq!(scoped_rules("", "# comment\n\n*.o\n/build\n"), "*.o\n/build\n");
+    }
+
+    #[test]
+    fn rules_without_a_slash_match_at_any_depth_below_the_directory() {
+        assert_eq!(scoped_rules("src", "*.o\ntarget/\n"), "/src/**/*.o\n/src/**/target/\n");
+    }
+
+    #[test]
+    fn rules_with_a_slash_are_anchored_to_the_directory() {
+        assert_eq!(scoped_rules("src", "/gen\nlib/out\n"), "/src/gen\n/src/lib/out\n");
+    }
+
+    #[test]
+    fn negations_keep_their_bang() {
+        assert_eq!(scoped_rules("a/b", "!keep.o\n"), "!/a/b/**/keep.o\n");
+    }
+
+    #[test]
+    fn directory_rules_only_match_directories() {
+        let rules = rules("", "target/\n");
+        assert!(rules.matches("target/"));
+        assert!(rules.matches("src/target/"));
+        assert!(!rules.matches("target"));
+        assert!(rules.matches("target/debug/app"));
+    }
+
+    #[test]
+    fn scoped_rules_match_below_their_directory() {
+        let rules = rules("src", "*.o\n/gen\n");
+        assert!(rules.matches("src/a.o"));
+        assert!(rules.matches("src/lib/a.o"));
+        assert!(!rules.matches("a.o"));
+        assert!(rules.matches("src/gen"));
+        assert!(!rules.matches("src/lib/gen"));
+    }
+
+    #[test]
+    fn the_last_matching_rule_decides() {
+        let rules = rules("", "*.log\n!keep.log\n");
+        assert!(rules.matches("a.log"));
+        assert!(!rules.matches("keep.log"));
+    }
+
+    #[test]
+    fn nothing_inside_an_ignored_directory_is_taken_back_in() {
+        let rules = rules("", "build/\n!build/keep.txt\n");
+        assert!(rules.matches("build/keep.txt"));
+    }
+}
@@ -0,0 +1,147 @@
+use git2::Oid;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// A change to the mounted tree that is not in a commit yet.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// The file at the path now has the content of this blob.
+    Blob(Oid),
+    /// The file at the path has writes that are not in a blob yet.
+    Dirty,
+    /// The path was removed.
+    Remove,
+    /// A directory was created at the path.
+    Mkdir,
+    /// The file at the path got this git file mode.
+    Mode(i32),
+}
+
+/// Write-ahead log of uncommitted changes, kept in the .git directory so a
+/// mount of the same repository and reference can pick them up after a crash.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+impl Journal {
+    /// Opens the journal for `referance`, returning the changes left over from an earlier mount.
+    pub fn open(git_dir: &Path, referance: &str) -> io::Result<(Journal, Vec<(String, Change)>)> {
+        let name = format!("gitfs-journal-{}", referance.replace('/', "_"));
+        let path = git_dir.join(name);
+        let mut leftover = Vec::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                if let Some(change) = parse(&line?) {
+                    leftover.push(change);
+                }
+            }
+        }
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok((Journal { path, file }, leftover))
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn record(&mut self, path: &str, change: &Change) -> io::Result<()> {
+        let line = match *change {
+            Change::Blob(oid) => format!("blob {} {}\n", oid, path),
+            Change::Dirty => format!("dirty {}\n", path),
+            Change::Remove => format!("remove {}\n", path),
+            Change::Mkdir => format!("mkdir {}\n", path),
+            Change::Mode(mode) => format!("mode {:o} {}\n", mode, path),
+        };
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+    /// Makes sure the journal itself survives a crash, records are already synced as they are made.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+    /// Forgets every recorded change, called once they are all in a commit.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+}
+
+fn parse(line: &str) -> Option<(String, Change)> {
+    let mut parts = line.splitn(2, ' ');
+    let kind = parts.next()?;
+    let rest = parts.next()?;
+    match kind {
+        "blob" => {
+            let mut parts = rest.splitn(2, ' ');
+            let oid = Oid::from_str(parts.next()?).ok()?;
+            Some((parts.next()?.to_owned(), Change::Blob(oid)))
+        }
+        "dirty" => Some((rest.to_owned(), Change::Dirty)),
+        "remove" => Some((rest.to_owned(), Change::Remove)),
+        "mkdir" => Some((rest.to_owned(), Change::Mkdir)),
+        "mode" => {
+            let mut parts = rest.splitn(2, ' ');
+            let mode = i32::from_str_radix(parts.next()?, 8).ok()?;
+            Some((parts.next()?.to_owned(), Change::Mode(mode)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Change, Journal};
+    use git2::Oid;
+    use tempdir::TempDir;
+
+    #[test]
+    fn changes_are_left_over_for_the_next_open() {
+        let dir = TempDir::new("gitfs-journal").unwrap();
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        {
+            let (mut journal, leftover) = Journal::open(dir.path(), "refs/heads/master").unwrap();
+            assert!(leftover.is_empty());
+            journal.record("a b", &Change::Blob(oid)).unwrap();
+            journal.record("c", &Change::Dirty).unwrap();
+            journal.record("d", &Change::Remove).unwrap();
+            journal.record("e", &Change::Mkdir).unwrap();
+            journal.record("f", &Change::Mode(0o100755)).unwrap();
+        }
+        assert!(dir.path().join("gitfs-journal-refs_heads_master").is_file());
+        let (_, leftover) = Journal::open(dir.path(), "refs/heads/master").unwrap();
+        assert_eq!(
+            leftover,
+            vec![
+                ("a b".to_owned(), Change::Blob(oid)),
+                ("c".to_owned(), Change::Dirty),
+                ("d".to_owned(), Change::Remove),
+                ("e".to_owned(), Change::Mkdir),
+                ("f".to_owned(), Change::Mode(0o100755)),
+            ]
+        );
+    }
+
+    #[test]
+    fn clear_forgets_everything_recorded_before() {
+        let dir = TempDir::new("gitfs-journal").unwrap();
+        {
+            let (mut journal, _) = Journal::open(dir.path(), "HEAD").unwrap();
+            journal.record("a", &Change::Dirty).unwrap();
+            journal.clear().unwrap();
+            journal.record("b", &Change::Dirty).unwrap();
+        }
+        let (_, leftover) = Journal::open(dir.path(), "HEAD").unwrap();
+        assert_eq!(leftover, vec![("b".to_owned(), Change::Dirty)]);
+    }
+
+    #[test]
+    fn unknown_and_broken_lines_are_skipped() {
+        assert_eq!(parse("rename a b"), None);
+        assert_eq!(parse("blob nothex a"), None);
+        assert_eq!(parse("mode 9 a"), None);
+        assert_eq!(parse("dirty"), None);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets in microseconds, slower operations only count towards +Inf.
+pub static LATENCY_BUCKETS: [u64; 10] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 1_000_000];
+
+/// Counts and latencies of one kind of filesystem operation.
+#[derive(Default)]
+pub struct OpStats {
+    pub count: u64,
+    pub errors: u64,
+    pub total_us: u64,
+    /// Operations per bucket of LATENCY_BUCKETS, not cumulative.
+    pub buckets: [u64; 10],
+}
+
+/// What the mount has done since it started.
+#[derive(Default)]
+pub struct Metrics {
+    pub ops: BTreeMap<&'static str, OpStats>,
+    /// Reads of a blob that was still in the read cache.
+    pub cache_hits: u64,
+    /// Reads of a blob that had to be loaded from the repository.
+    pub cache_misses: u64,
+    pub blobs_written: u64,
+    pub commits: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+    pub fn record(&mut self, op: &'static str, micros: u64, errno: c_int) {
+        let stats = self.ops.entry(op).or_insert_with(OpStats::default);
+        stats.count += 1;
+        if errno != 0 {
+            stats.errors += 1;
+        }
+        stats.total_us += micros;
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| micros <= le) {
+            stats.buckets[bucket] += 1;
+        }
+    }
+    /// Share of blob reads served from the read cache, None before the first read.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            None
+        } else {
+            Some(self.cache_hits as f64 / reads as f64)
+        }
+    }
+    /// Contents of the stats file.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out += &format!("bytes read: {}\n", self.bytes_read);
+        out += &format!("bytes written: {}\n", self.bytes_written);
+        out += &format!("blobs written: {}\n", self.blobs_written);
+        out += &format!("commits: {}\n", self.commits);
+        match self.hit_rate() {
+            Some(rate) => {
+                out += &format!(
+                    "cache: {} hits {} misses {:.1}%\n",
+                    self.cache_hits,
+                    self.cache_misses,
+                    rate * 100.0
+                )
+            }
+            None => out += "cache: 0 hits 0 misses\n",
+        }
+        for (op, stats) in &self.ops {
+            out += &format!(
+                "op: {} count {} errors {} avg {}us",
+                op,
+                stats.count,
+                stats.errors,
+                stats.total_us / stats.count
+            );
+            for (le, n) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                out += &format!(" <={}us:{}", le, n);
+            }
+            let slower = stats.count - stats.buckets.iter().sum::<u64>();
+            out += &format!(" slower:{}\n", slower);
+        }
+        out
+    }
+    /// The metrics in the Prometheus text format.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        out += "# HELP gitfs_operations_total Filesystem operations served.\n";
+        out += "# TYPE gitfs_operations_total counter\n";
+        for (op, stats) in &self.ops {
+            out += &format!("gitfs_operations_total{{op=\"{}\"}} {}\n", op, stats.count);
+        }
+        out += "# HELP gitfs_operation_errors_total Filesystem operations that replied with an error.\n";
+        out += "# TYPE gitfs_operation_errors_total counter\n";
+        for (op, stats) in &self.ops {
+            out += &format!("gitfs_operation_errors_total{{op=\"{}\"}} {}\n", op, stats.errors);
+        }
+        out += "# HELP gitfs_operation_duration_seconds Time taken to serve filesystem operations.\n";
+        out += "# TYPE gitfs_operation_duration_seconds histogram\n";
+        for (op, stats) in &self.ops {
+            let mut cumulative = 0;
+            for (le, n) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                cumulative += n;
+                out += &format!(
+                    "gitfs_operation_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}\n",
+                    op,
+                    *le as f64 / 1e6,
+                    cumulative
+                );
+            }
+            out += &format!(
+                "gitfs_operation_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}\n",
+                op, stats.count
+            );
+            out += &format!(
+                "gitfs_operation_duration_seconds_sum{{op=\"{}\"}} {}\n",
+                op,
+                stats.total_us as f64 / 1e6
+            );
+            out += &format!("gitfs_operation_duration_seconds_count{{op=\"{}\"}} {}\n", op, stats.count);
+        }
+        let counters = [
+            ("gitfs_cache_hits_total", "Blob reads served from the read cache.", self.cache_hits),
+            ("gitfs_cache_misses_total", "Blob reads loaded from the repository.", self.cache_misses),
+            ("gitfs_blobs_written_total", "Blobs written to the repository.", self.blobs_written),
+            ("gitfs_commits_total", "Commits made.", self.commits),
+            ("gitfs_read_bytes_total", "Bytes read from files.", self.bytes_read),
+            ("gitfs_written_bytes_total", "Bytes written to files.", self.bytes_written),
+        ];
+        for &(name, help, value) in &counters {
+            out += &format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, value);
+        }
+        out
+    }
+}
+
+/// Locks the metrics, a thread that panicked while holding them leaves them usable.
+pub fn lock<'a>(metrics: &'a Mutex<Metrics>) -> MutexGuard<'a, Metrics> {
+    metrics.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Serves the metrics over HTTP at /metrics from a thread of its own.
+pub fn serve(listener: TcpListener, metrics: Arc<Mutex<Metrics>>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &metrics) {
+                        warn!("Failed to serve metrics: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    //Only the request line matters, anything after it is ignored
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut words = request.split_whitespace();
+    let found = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) => path == "/metrics" || path.starts_with("/metrics?"),
+        _ => false,
+    };
+    if !found {
+        return stream.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+    let body = lock(metrics).prometheus();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, LATENCY_BUCKETS};
+
+    #[test]
+    fn record_counts_errors_and_buckets_latency() {
+        let mut metrics = Metrics::new();
+        metrics.record("read", 10, 0);
+        metrics.record("read", 11, 5);
+        metrics.record("read", LATENCY_BUCKETS[9] + 1, 0);
+        let stats = &metrics.ops["read"];
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.total_us, 10 + 11 + LATENCY_BUCKETS[9] + 1);
+        assert_eq!(stats.buckets, [1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn hit_rate_is_unknown_before_the_first_read() {
+        let mut metrics = Metrics::new();
+        assert_eq!(metrics.hit_rate(), None);
+        metrics.cache_hits = 3;
+        metrics.cache_misses = 1;
+        assert_eq!(metrics.hit_rate(), Some(0.75));
+    }
+
+    #[test]
+    fn render_lists_counters_and_operations() {
+        let mut metrics = Metrics::new();
+        metrics.bytes_read = 42;
+        metrics.cache_hits = 1;
+        metrics.cache_misses = 1;
+        metrics.record("open", 20, 0);
+        metrics.record("open", 2_000_000, 0);
+        let out = metrics.render();
+        assert!(out.contains("bytes read: 42\n"));
+        assert!(out.contains("cache: 1 hits 1 misses 50.0%\n"));
+        assert!(out.contains("op: open count 2 errors 0 avg 1000010us <=10us:0 <=50us:1 "));
+        assert!(out.ends_with(" slower:1\n"));
+    }
+
+    #[test]
+    fn prometheus_buckets_are_cumulative() {
+        let mut metrics = Metrics::new();
+        metrics.commits = 2;
+        metrics.record("write", 5, 0);
+        metrics.record("write", 600, 28);
+        metrics.record("write", 2_000_000, 0);
+        let out = metrics.prometheus();
+        for line in &[
+            "gitfs_operations_total{op=\"write\"} 3",
+            "gitfs_operation_errors_total{op=\"write\"} 1",
+            "gitfs_operation_duration_seconds_bucket{op=\"write\",le=\"0.00001\"} 1",
+            "gitfs_operation_duration_seconds_bucket{op=\"write\",le=\"0.0005\"} 1",
+            "gitfs_operation_duration_seconds_bucket{op=\"write\",le=\"0.001\"} 2",
+            "gitfs_operation_duration_seconds_bucket{op=\"write\",le=\"1\"} 2",
+            "gitfs_operation_duration_seconds_bucket{op=\"write\",le=\"+Inf\"} 3",
+            "gitfs_operation_duration_seconds_count{op=\"write\"} 3",
+            "gitfs_commits_total 2",
+        ] {
+            assert!(out.lines().any(|l| l == *line), "{}", line);
+        }
+    }
+}
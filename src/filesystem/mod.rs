@@ -1,21 +1,206 @@
 pub mod access_codes;
+mod control;
+pub mod error;
 pub mod error_codes;
 mod filesystem_entry;
+mod hooks;
+mod ignore;
+mod journal;
+mod metrics;
+pub mod options;
+mod protect;
+mod quota;
+mod remote;
+mod scratch;
+mod secrets;
+#[cfg(test)]
+mod tests;
+mod trace;
+mod usage;
 
 use fuse::*;
 use git2::{Oid, Repository, Signature,Index};
 
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::io::Write;
+use std::net::TcpListener;
 use std::path::Path;
 use std::vec::Vec;
 
+use libc::{R_OK, W_OK, X_OK};
 use std::os::raw::c_int;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use time::Timespec;
 
+use self::control::{PushState, Status};
+use self::error::Error;
+use self::journal::{Change, Journal};
+use self::metrics::Metrics;
+use self::quota::Quota;
+use self::scratch::Scratch;
+use self::secrets::Scanner;
+use self::trace::Trace;
+
+pub use self::options::{Options, Recovery};
+pub use self::protect::Protected;
+pub use self::secrets::default_rules as default_secret_rules;
+
 // TODO: Check all error codes
 
+/// Permission bits for an entry of the given git file mode, git only tracks
+/// whether a file is executable so everything else is synthesized.
+fn permissions(file_type: FileType, file_mode: i32) -> u16 {
+    match file_type {
+        FileType::Directory => 0o755,
+        _ if file_mode & 0o111 != 0 => 0o755,
+        _ => 0o644,
+    }
+}
+
+/// The git file mode for a regular file created or changed to `mode`, git only
+/// tracks whether the file is executable.
+fn file_mode(mode: u32) -> i32 {
+    if mode & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+/// True if the user making `req` may access a file with `attrs` as asked by `mask`.
+/// Supplementary groups are not known, only the primary group of the request counts.
+fn permitted(req: &Request, attrs: &FileAttr, mask: i32) -> bool {
+    let mask = (mask & (R_OK | W_OK | X_OK)) as u16;
+    if req.uid() == 0 {
+        //root may do anything but execute files nobody can execute
+        return mask & X_OK as u16 == 0
+            || attrs.kind == FileType::Directory
+            || attrs.perm & 0o111 != 0;
+    }
+    let granted = if req.uid() == attrs.uid {
+        attrs.perm >> 6
+    } else if req.gid() == attrs.gid {
+        attrs.perm >> 3
+    } else {
+        attrs.perm
+    } & 0o7;
+    mask & !granted == 0
+}
+
+/// Joins a directory path from the inode table with the name of an entry in it.
+fn join_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// The part of `data` a read of `size` bytes at `offset` covers.
+fn slice_at(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let start = (offset as usize).min(data.len());
+    let end = (start + size as usize).min(data.len());
+    &data[start..end]
+}
+
+/// The errno to reply with for a failed IO operation.
+fn io_errno(e: &::std::io::Error) -> c_int {
+    e.raw_os_error().unwrap_or(error_codes::EIO)
+}
+
+/// Takes a file out of its hard link group, called when it is removed or its
+/// content diverges from the rest of the group.
+fn leave_link_group(links: &mut HashMap<usize, u32>, entry: &mut filesystem_entry::FilesystemEntry) {
+    if let Some(group) = entry.link.take() {
+        let empty = match links.get_mut(&group) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if empty {
+            links.remove(&group);
+        }
+    }
+}
+
+/// Takes every file in the directory `entry` out of its hard link group, called when it is removed.
+fn leave_link_groups(links: &mut HashMap<usize, u32>, entry: &mut filesystem_entry::FilesystemEntry) {
+    leave_link_group(links, entry);
+    for child in entry.children.iter_mut() {
+        leave_link_groups(links, child);
+    }
+}
+
+/// Flushes the loose object file of `oid` to disk, objects that are packed
+/// were already in the repository and are left alone.
+fn sync_object(repository: &Repository, oid: Oid) -> ::std::io::Result<()> {
+    let hex = oid.to_string();
+    let dir = repository.path().join("objects").join(&hex[..2]);
+    match ::std::fs::File::open(dir.join(&hex[2..])) {
+        Ok(file) => file.sync_all()?,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    ::std::fs::File::open(dir)?.sync_all()
+}
+
+/// Writes the dirty content of `entry` to the repository as a blob and returns
+/// it, local entries only get their size updated since they never become blobs.
+fn store_buffer(
+    repository: &Repository,
+    entry: &mut filesystem_entry::FilesystemEntry,
+    scratch: Option<&Scratch>,
+    quota: &mut Quota,
+    metrics: &Mutex<Metrics>,
+    path: &str,
+) -> Result<Option<Oid>, c_int> {
+    if entry.local {
+        entry.size = entry.buffer_len(scratch, path).map_err(|e| io_errno(&e))?;
+        return Ok(None);
+    }
+    let len = entry.buffer_len(scratch, path).map_err(|e| io_errno(&e))?;
+    //Only hash ahead of writing when the quota needs to know if the blob is new
+    let mut cost = 0;
+    if quota.remaining().is_some() {
+        let oid = entry.store(None, scratch, path).map_err(|e| Error::from(e).errno())?;
+        cost = quota.check_blob(repository, oid, len)?;
+    }
+    match entry.store(Some(repository), scratch, path) {
+        Ok(oid) => {
+            quota.charge(cost);
+            metrics::lock(metrics).blobs_written += 1;
+            entry.size = len;
+            entry.oid = Some(oid);
+            Ok(Some(oid))
+        }
+        Err(e) => {
+            error!("Failed to store {}: {}", path, e);
+            Err(Error::from(e).errno())
+        }
+    }
+}
+
+/// Block size reported by statfs.
+static BLOCK_SIZE: u64 = 4096;
+/// Longest file name reported by statfs, git has no limit but the scratch directory does.
+static NAME_MAX: u32 = 255;
+
+/// Largest blob kept in the read cache, bigger ones are read from the repository every time.
+static READ_CACHE_MAX: usize = 8 * 1024 * 1024;
+/// How long a commit that failed in a way that may go away by itself waits before `tick` tries it again.
+static COMMIT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long a commit that keeps failing waits before `tick` tries it again.
+static COMMIT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a failed push when pushing after every commit.
+static PUSH_RETRY_DELAY: Duration = Duration::from_secs(10);
+
 pub struct GitFilesystem<'collection> {
     repository: Repository,
     new_tree: Oid,
@@ -25,48 +210,63 @@ pub struct GitFilesystem<'collection> {
     files: filesystem_entry::FilesystemEntry,
     ttl: i64,
     change_counter: usize,
+    options: Options,
+    /// What the background fetch fetches, None if the mount does not fetch.
+    fetch: Option<remote::Fetch>,
+    /// Set by the background fetch when the branch can be fast-forwarded.
+    behind: Arc<AtomicBool>,
+    last_push: Instant,
+    push_attempts: u32,
+    /// Gets the outcome of the push running in the background, None while no push runs.
+    pushing: Option<Receiver<(Oid, Result<(), PushState>)>>,
+    status: Status,
+    scratch: Option<Scratch>,
+    journal: Option<Journal>,
+    commit_failed: Arc<AtomicBool>,
+    scanner: Option<Scanner>,
+    quota: Quota,
+    links: HashMap<usize, u32>,
+    next_link: usize,
+    last_commit_attempt: Instant,
+    /// How long after `last_commit_attempt` a failed commit is tried again.
+    commit_retry_delay: Duration,
+    metrics: Arc<Mutex<Metrics>>,
+    /// The blob read last, reads of a file usually come one chunk after another.
+    read_cache: Option<(Oid, Vec<u8>)>,
+    /// Rules from the .gitignore files in the mounted tree.
+    ignore: ignore::Rules,
+    /// Where the hooks run, created with the first commit that runs them.
+    hook_checkout: Option<hooks::Checkout>,
+    usage: usage::ObjectUsage,
 }
 impl<'collection> GitFilesystem<'collection> {
-    pub fn new(repo_path: &str, referance: &'collection str) -> GitFilesystem<'collection> {
-        let mut repository = match Repository::open(repo_path) {
-            Ok(repo) => repo,
-            Err(e) => panic!("failed to open: {}", e),
-        };
-        let mut commit_time;
-        let mut new_tree;
-        let mut files;
-        let mut inods = Vec::new();
+    pub fn new(
+        repo_path: &str,
+        referance: &'collection str,
+        options: Options,
+    ) -> error::Result<GitFilesystem<'collection>> {
+        let repository = Repository::open(repo_path)?;
+        let (files, inods, commit_time) = GitFilesystem::read_tree(&repository, referance)?;
+        let scanner = Scanner::new(options.secret_rules.clone(), options.secret_entropy);
+        let quota = Quota::new(options.max_file_size, options.object_quota);
+        let scratch = match options.scratch_dir {
+            Some(ref dir) => Some(Scratch::new(dir)?),
+            None => None,
+        };
+        let new_tree;
         {
-            //TODO: might want to use as_commit() instead of peel_to_commit
-            let curr_commit = repository
-                .revparse_single(referance)
-                .unwrap()
-                .peel_to_commit()
-                .unwrap();
-            let curr_tree = curr_commit.tree().unwrap();
-            inods.push("".to_string());
-            inods.push("".to_string()); //filesys inode starts at 1, this is faster then to add and sub everytime.
-
-            files = filesystem_entry::FilesystemEntry::from_tree(
-                &curr_tree,
-                &repository,
-                "".to_string(),
-                "".to_string(),
-                &mut inods,
-                0o040000,
-            );
+            let curr_tree = repository.revparse_single(referance)?.peel_to_tree()?;
 
             //Writes a copy of the current tree to git and saves the Oid, this is to hinder the original tree from getting deleted.
-            new_tree = repository
-                .treebuilder(Some(&curr_tree))
-                .unwrap()
-                .write()
-                .unwrap();
-
-            //commit do not have nano seconds so sett it to 0
-            commit_time = Timespec::new(curr_commit.time().seconds(), 0);
+            new_tree = repository.treebuilder(Some(&curr_tree))?.write()?;
+        }
+        let usage = usage::ObjectUsage::new(repository.path());
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        if let Some(port) = options.metrics_port {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            metrics::serve(listener, metrics.clone());
         }
-        GitFilesystem {
+        let mut filesystem = GitFilesystem {
             repository,
             new_tree,
             commit_time,
@@ -75,12 +275,491 @@ impl<'collection> GitFilesystem<'collection> {
             ttl: 10,
             files,
             change_counter : 0,
+            options,
+            fetch: None,
+            behind: Arc::new(AtomicBool::new(false)),
+            last_push: Instant::now(),
+            push_attempts: 0,
+            pushing: None,
+            status: Status::new(),
+            scratch,
+            journal: None,
+            commit_failed: Arc::new(AtomicBool::new(false)),
+            scanner,
+            quota,
+            links: HashMap::new(),
+            next_link: 0,
+            last_commit_attempt: Instant::now(),
+            commit_retry_delay: COMMIT_RETRY_INTERVAL,
+            metrics,
+            read_cache: None,
+            ignore: ignore::Rules::new(),
+            hook_checkout: None,
+            usage,
+        };
+        filesystem.load_ignore_rules();
+        filesystem.warn_shadowed();
+        match Journal::open(filesystem.repository.path(), referance) {
+            Ok((journal, leftover)) => {
+                filesystem.journal = Some(journal);
+                filesystem.recover(leftover);
+            }
+            Err(e) => error!("Failed to open journal, changes will not survive a crash: {}", e),
+        }
+        filesystem.start_fetching();
+        filesystem.usage.refresh();
+        Ok(filesystem)
+    }
+
+    /// Flag that is set while the last commit attempt has failed, it outlives the
+    /// filesystem so the final commit made when it is dropped can be checked.
+    pub fn commit_failed(&self) -> Arc<AtomicBool> {
+        self.commit_failed.clone()
+    }
+
+    /// Records an uncommitted change in the journal.
+    fn record(&mut self, path: &str, change: &Change) {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(e) = journal.record(path, change) {
+                error!("Failed to write {}: {}", journal.path().display(), e);
+            }
+        }
+    }
+
+    fn clear_journal(&mut self) {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(e) = journal.clear() {
+                error!("Failed to clear {}: {}", journal.path().display(), e);
+            }
+        }
+    }
+
+    /// Applies the changes an earlier mount left in the journal, as chosen by `options.recovery`.
+    fn recover(&mut self, leftover: Vec<(String, Change)>) {
+        if leftover.is_empty() {
+            return;
+        }
+        if let Recovery::Discard = self.options.recovery {
+            info!("Discarding {} uncommitted changes from an earlier mount", leftover.len());
+            self.clear_journal();
+            return;
+        }
+        info!("Restoring {} uncommitted changes from an earlier mount", leftover.len());
+        let mut restored = Vec::new();
+        for (path, change) in leftover {
+            match self.restore(path.as_str(), change) {
+                Ok(Some(change)) => restored.push((path, change)),
+                Ok(None) => {}
+                Err(e) => error!("Could not restore {}: {}", path, e),
+            }
+        }
+        //Rewrite the journal with what was restored, dirty files have become blobs by now
+        self.clear_journal();
+        for (path, change) in restored {
+            self.record(path.as_str(), &change);
+        }
+        if let Recovery::Commit = self.options.recovery {
+            self.commit();
+        }
+    }
+
+    fn restore(&mut self, path: &str, change: Change) -> Result<Option<Change>, String> {
+        let oid = match change {
+            Change::Blob(oid) => oid,
+            Change::Dirty => {
+                let file = match self.scratch {
+                    Some(ref scratch) => scratch.path(path),
+                    None => return Err("the changes were only kept in memory".to_owned()),
+                };
+                let oid = self.repository.blob_path(&file).map_err(|e| e.to_string())?;
+                metrics::lock(&self.metrics).blobs_written += 1;
+                if let Some(ref scratch) = self.scratch {
+                    if let Err(e) = scratch.remove(path) {
+                        error!("Failed to remove scratch for {}: {}", path, e);
+                    }
+                }
+                oid
+            }
+            Change::Remove => {
+                let (parent, name) = match path.rfind('/') {
+                    Some(i) => (&path[..i], &path[i + 1..]),
+                    None => ("", path),
+                };
+                let file_type = match self.files.get_path(path) {
+                    Some(e) => e.file_type,
+                    None => return Ok(None),
+                };
+                return match self.files.get_path_mut(parent) {
+                    Some(dir) => match dir.remove(name, file_type, &mut self.inods) {
+                        Ok(_) => Ok(Some(Change::Remove)),
+                        Err(e) => Err(format!("errno {}", e)),
+                    },
+                    None => Ok(None),
+                };
+            }
+            Change::Mkdir => {
+                self.make_path(path, FileType::Directory)?;
+                return Ok(Some(Change::Mkdir));
+            }
+            Change::Mode(mode) => {
+                self.make_path(path, FileType::RegularFile)?.file_mode = mode;
+                return Ok(Some(Change::Mode(mode)));
+            }
+        };
+        let size = match self.repository.find_blob(oid) {
+            Ok(blob) => blob.content().len() as u64,
+            Err(e) => return Err(e.to_string()),
+        };
+        let entry = self.make_path(path, FileType::RegularFile)?;
+        entry.oid = Some(oid);
+        entry.size = size;
+        Ok(Some(Change::Blob(oid)))
+    }
+
+    /// Finds the entry at `path`, creating it and any missing directories above it.
+    fn make_path(
+        &mut self,
+        path: &str,
+        file_type: FileType,
+    ) -> Result<&mut filesystem_entry::FilesystemEntry, String> {
+        let names: Vec<&str> = path.split('/').collect();
+        let mut parent = String::new();
+        for (i, name) in names.iter().enumerate() {
+            let full_path = join_path(&parent, name);
+            if self.files.get_path(full_path.as_str()).is_none() {
+                let (file_type, file_mode) = if i + 1 == names.len() && file_type == FileType::RegularFile {
+                    (FileType::RegularFile, 0o100644)
+                } else {
+                    (FileType::Directory, 0o040000)
+                };
+                let entry = filesystem_entry::FilesystemEntry::new(
+                    file_type,
+                    name.to_string(),
+                    parent.clone(),
+                    &mut self.inods,
+                    file_mode,
+                );
+                match self.files.get_path_mut(parent.as_str()) {
+                    Some(ref mut dir) if dir.file_type == FileType::Directory => {
+                        dir.add(entry);
+                    }
+                    _ => return Err(format!("{} is not a directory", parent)),
+                }
+            }
+            parent = full_path;
+        }
+        match self.files.get_path_mut(path) {
+            Some(entry) => {
+                if entry.file_type == file_type {
+                    Ok(entry)
+                } else {
+                    Err("a different kind of file is in the way".to_owned())
+                }
+            }
+            None => Err("not found".to_owned()),
+        }
+    }
+
+    /// Replaces the ignore rules with the .gitignore files of the mounted tree,
+    /// so new paths are checked against what will be committed.
+    fn load_ignore_rules(&mut self) {
+        let mut found = Vec::new();
+        self.files.gitignores("", &mut found);
+        self.ignore = ignore::Rules::new();
+        for (dir, oid) in found {
+            let rules = match self.repository.find_blob(oid) {
+                Ok(blob) => ignore::scoped_rules(&dir, &String::from_utf8_lossy(blob.content())),
+                Err(e) => {
+                    error!("Failed to read {}/.gitignore: {}", dir, e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.ignore.add(&rules) {
+                warn!("Invalid ignore rules in {}/.gitignore: {}", dir, e);
+            }
+        }
+    }
+
+    /// True if a new entry at `path` should stay local, either because it is
+    /// inside a local directory or because the ignore rules match it. Directories
+    /// are given with a trailing slash.
+    fn is_local(&self, parent: &str, path: &str) -> bool {
+        let parent_local = match self.files.get_path(parent) {
+            Some(p) => p.local,
+            None => false,
+        };
+        parent_local || self.ignore.matches(path)
+    }
+
+    /// Warns about files in the root of the tree that a control file of the same name hides.
+    fn warn_shadowed(&self) {
+        for name in &[control::STATUS_NAME, control::STATS_NAME] {
+            if self.files.index(name).is_some() {
+                warn!("{} in the tree is hidden by the control file of the same name", name);
+            }
+        }
+    }
+
+    /// Builds the file tree and inode table for the commit `referance` points at.
+    fn read_tree(
+        repository: &Repository,
+        referance: &str,
+    ) -> Result<(filesystem_entry::FilesystemEntry, Vec<String>, Timespec), git2::Error> {
+        let mut inods = Vec::new();
+        //TODO: might want to use as_commit() instead of peel_to_commit
+        let curr_commit = repository.revparse_single(referance)?.peel_to_commit()?;
+        let curr_tree = curr_commit.tree()?;
+        inods.push("".to_string());
+        inods.push("".to_string()); //filesys inode starts at 1, this is faster then to add and sub everytime.
+        inods.push(control::STATUS_NAME.to_string()); //served by GitFilesystem, not found in the tree.
+        inods.push(control::STATS_NAME.to_string());
+
+        let files = filesystem_entry::FilesystemEntry::from_tree(
+            &curr_tree,
+            repository,
+            "".to_string(),
+            "".to_string(),
+            &mut inods,
+            0o040000,
+        )?;
+
+        //commit do not have nano seconds so sett it to 0
+        let commit_time = Timespec::new(curr_commit.time().seconds(), 0);
+        Ok((files, inods, commit_time))
+    }
+
+    /// Runs the periodic work that is due, called from the frequent FUSE operations.
+    fn tick(&mut self) {
+        if self.behind.swap(false, Ordering::SeqCst) {
+            if let Err(e) = self.fast_forward() {
+                warn!("Fast-forward failed: {}", e);
+            }
+        }
+        let retry_commit = self.commit_failed.load(Ordering::SeqCst)
+            && self.status.hook_failure.is_none()
+            && self.last_commit_attempt.elapsed() >= self.commit_retry_delay;
+        if retry_commit {
+            self.commit();
+        }
+        self.poll_push();
+        let delay = self.options.push_interval.unwrap_or(PUSH_RETRY_DELAY);
+        let push_due = match self.status.push {
+            Some(PushState::Pending) => self.last_push.elapsed() >= delay,
+            Some(PushState::Failed(_)) => {
+                self.push_attempts <= self.options.push_retries
+                    && self.last_push.elapsed() >= delay
+            }
+            _ => false,
+        };
+        if push_due {
+            self.push();
+        }
+    }
+
+    /// Resolves `referance` to the branch it names, returning its full name,
+    /// short name and the commit it points at.
+    fn branch(&self) -> Result<(String, String, Oid), git2::Error> {
+        let (_, reference) = self.repository.revparse_ext(self.referance)?;
+        let branch = match reference {
+            Some(r) => r.resolve()?,
+            None => return Err(git2::Error::from_str("not a branch")),
+        };
+        if !branch.is_branch() {
+            return Err(git2::Error::from_str("not a branch"));
+        }
+        match (branch.name(), branch.shorthand(), branch.target()) {
+            (Some(name), Some(short), Some(oid)) => Ok((name.to_owned(), short.to_owned(), oid)),
+            _ => Err(git2::Error::from_str("branch name is not valid utf-8")),
+        }
+    }
+
+    /// True if the mounted tree differs from the commit `referance` points at.
+    fn has_pending_changes(&self) -> bool {
+        if self.files.is_writing() {
+            return true;
+        }
+        let head_tree = match self
+            .repository
+            .revparse_single(self.referance)
+            .and_then(|o| o.peel_to_tree())
+        {
+            Ok(tree) => tree.id(),
+            Err(_) => return true,
+        };
+        let placeholder = self.options.placeholder.as_ref().map(String::as_str);
+        match self.files.tree_id(placeholder) {
+            Ok(tree) => tree != Some(head_tree),
+            Err(_) => true,
+        }
+    }
+
+    /// Starts fetching the mounted branch from the configured remote in the
+    /// background, `tick` fast-forwards to what it brings in.
+    fn start_fetching(&mut self) {
+        let (remote_name, interval) = match (self.options.remote.clone(), self.options.fetch_interval) {
+            (Some(r), Some(i)) => (r, i),
+            _ => return,
+        };
+        let (branch, branch_name, _) = match self.branch() {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("Not fetching {}: {}", self.referance, e);
+                return;
+            }
+        };
+        let fetch = remote::Fetch {
+            tracking: format!("refs/remotes/{}/{}", remote_name, branch_name),
+            remote: remote_name,
+            branch,
+        };
+        remote::fetch_periodically(
+            self.repository.path().to_owned(),
+            fetch.clone(),
+            interval,
+            self.behind.clone(),
+        );
+        self.fetch = Some(fetch);
+    }
+
+    /// Fast-forwards the local branch and the mounted tree to what the last
+    /// fetch brought in, if nothing is pending locally. Paths that are in both
+    /// trees keep their inodes, the kernel may still have them cached.
+    fn fast_forward(&mut self) -> Result<(), git2::Error> {
+        let fetch = match self.fetch {
+            Some(ref f) => f.clone(),
+            None => return Ok(()),
+        };
+        let (local_oid, remote_oid) = fetch.heads(&self.repository)?;
+        if remote_oid == local_oid
+            || !self.repository.graph_descendant_of(remote_oid, local_oid)?
+        {
+            return Ok(());
+        }
+        if self.has_pending_changes() {
+            info!("Local changes pending, not fast-forwarding to {}", remote_oid);
+            return Ok(());
+        }
+        self.repository
+            .find_reference(fetch.branch.as_str())?
+            .set_target(remote_oid, "gitfs: fast-forward")?;
+        let (mut files, _, commit_time) = GitFilesystem::read_tree(&self.repository, self.referance)?;
+        //Carry the local overlay and uncommitted empty directories over to the new tree,
+        //they keep their inodes
+        let mut uncommitted = Vec::new();
+        let placeholder = self.options.placeholder.as_ref().map(String::as_str);
+        self.files.take_uncommitted("", placeholder, &mut uncommitted);
+        let mut known = HashMap::new();
+        self.files.inodes("", &mut known);
+        files.remap("", &known, &mut self.inods);
+        for (parent, entry) in uncommitted {
+            match files.get_path_mut(parent.as_str()) {
+                Some(dir) => dir.carry(entry),
+                None => warn!("Dropping {}/{}, its directory is gone", parent, entry.name),
+            }
+        }
+        self.files = files;
+        //The new tree has no hard links, every file is its own again
+        self.links.clear();
+        self.commit_time = commit_time;
+        self.load_ignore_rules();
+        self.warn_shadowed();
+        info!("Fast-forwarded to {}", remote_oid);
+        Ok(())
+    }
+
+    /// Starts pushing the mounted branch to the configured push remote in the
+    /// background, failed attempts are retried from `tick` until `push_retries` is used up.
+    fn push(&mut self) {
+        let remote_name = match self.options.push_remote {
+            Some(ref r) => r.clone(),
+            None => return,
+        };
+        //The next push starts when this one is done
+        if self.pushing.is_some() {
+            return;
+        }
+        self.last_push = Instant::now();
+        self.push_attempts += 1;
+        let (branch_ref, _, oid) = match self.branch() {
+            Ok(b) => b,
+            Err(e) => return self.push_done(Err(PushState::Failed(e.to_string()))),
+        };
+        let refspec = match self.options.push_refspec {
+            Some(ref r) => r.clone(),
+            None => format!("{0}:{0}", branch_ref),
+        };
+        let push = remote::Push {
+            remote: remote_name,
+            refspec,
+        };
+        self.pushing = Some(remote::push_in_background(self.repository.path().to_owned(), push, oid));
+    }
+
+    /// Takes the outcome of the push running in the background, if it is done.
+    fn poll_push(&mut self) {
+        let done = match self.pushing {
+            Some(ref receiver) => match receiver.try_recv() {
+                Ok(done) => Some(done),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => None,
+            },
+            None => return,
+        };
+        self.pushing = None;
+        self.pushed(done);
+    }
+
+    /// Waits for the push running in the background, and any push it leads to, to finish.
+    fn wait_for_push(&mut self) {
+        while let Some(receiver) = self.pushing.take() {
+            self.pushed(receiver.recv().ok());
+        }
+    }
+
+    /// Handles the outcome of a background push of a commit, None if the push thread went away.
+    fn pushed(&mut self, done: Option<(Oid, Result<(), PushState>)>) {
+        let oid = match done {
+            Some((oid, result)) => {
+                self.push_done(result.map(|_| oid));
+                oid
+            }
+            None => return self.push_done(Err(PushState::Failed("the push stopped".to_owned()))),
+        };
+        //A commit made while the push ran still has to go out
+        if self.status.last_commit.map_or(false, |last| last != oid) {
+            self.status.push = Some(PushState::Pending);
+            self.push_attempts = 0;
+            if self.options.push_interval.is_none() {
+                self.push();
+            }
         }
     }
 
+    fn push_done(&mut self, result: Result<Oid, PushState>) {
+        let remote_name = self.options.push_remote.clone().unwrap_or_default();
+        match result {
+            Ok(oid) => {
+                info!("Pushed {} to {}", oid, remote_name);
+                self.status.push = Some(PushState::Pushed(oid));
+                self.push_attempts = 0;
+            }
+            Err(PushState::Rejected(e)) => {
+                warn!("Push to {} rejected: {}", remote_name, e);
+                self.status.push = Some(PushState::Rejected(e));
+                self.push_attempts = 0;
+            }
+            Err(PushState::Failed(e)) => {
+                error!(
+                    "Push to {} failed (attempt {}/{}): {}",
+                    remote_name, self.push_attempts, self.options.push_retries + 1, e
+                );
+                self.status.push = Some(PushState::Failed(e));
+            }
+            Err(_) => {}
+        }
+    }
 
     fn get_attrs(&self, entry: &filesystem_entry::FilesystemEntry) -> FileAttr {
-        //TODO: find out what we can get from entry.filemode()
         let mut file_attr = FileAttr {
             ino: entry.ino as u64,
             size: 0,
@@ -89,10 +768,10 @@ impl<'collection> GitFilesystem<'collection> {
             mtime: self.commit_time,
             ctime: self.commit_time,
             kind: entry.file_type,
-            perm: 0,
+            perm: permissions(entry.file_type, entry.file_mode) & !self.options.umask,
             nlink: 1,
-            uid: 0,
-            gid: 0,
+            uid: self.options.uid,
+            gid: self.options.gid,
             rdev: 0,
             flags: 0,
             crtime: self.commit_time, // TODO: Get repository creation time maybe?
@@ -104,57 +783,524 @@ impl<'collection> GitFilesystem<'collection> {
             }
             FileType::RegularFile => {
                 file_attr.size = entry.size;
+                file_attr.nlink = entry
+                    .link
+                    .and_then(|group| self.links.get(&group))
+                    .cloned()
+                    .unwrap_or(1);
+            }
+            _ => {}
+        };
+        file_attr
+    }
+
+    /// Checks that the user making `req` may access the entry at `path` as asked
+    /// by `mask`, a combination of R_OK, W_OK and X_OK.
+    fn check_access(&self, req: &Request, path: &str, mask: i32) -> Result<(), c_int> {
+        let entry = match self.files.get_path(path) {
+            Some(e) => e,
+            None => return Err(error_codes::ENOENT),
+        };
+        if mask & W_OK != 0 {
+            self.check_protected(path)?;
+        }
+        if permitted(req, &self.get_attrs(entry), mask) {
+            Ok(())
+        } else {
+            Err(error_codes::EACCES)
+        }
+    }
+
+    /// Checks that nothing is at `path` yet and that `dir` is a directory to create it in,
+    /// before an inode is allocated for it.
+    fn check_new(&self, dir: &str, path: &str) -> Result<(), c_int> {
+        if self.files.get_path(path).is_some() {
+            return Err(error_codes::EEXIST);
+        }
+        match self.files.get_path(dir) {
+            Some(e) if e.file_type == FileType::Directory => Ok(()),
+            Some(_) => Err(error_codes::ENOTDIR),
+            None => Err(error_codes::ENOENT),
+        }
+    }
+
+    /// Fails with EACCES if `path` is protected from changes.
+    fn check_protected(&self, path: &str) -> Result<(), c_int> {
+        if self.options.protected.matches(path) {
+            Err(error_codes::EACCES)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Creates the directory `name` in `dir`, local if the ignore rules match it.
+    fn make_dir(&mut self, dir: &str, name: String) -> Result<FileAttr, c_int> {
+        let full_path = join_path(dir, &name);
+        self.check_protected(&full_path)?;
+        self.check_new(dir, &full_path)?;
+        let local = self.is_local(dir, &format!("{}/", full_path));
+        let mut new_file = filesystem_entry::FilesystemEntry::new(
+            FileType::Directory,
+            name,
+            dir.to_string(),
+            &mut self.inods,
+            0o040000,
+        );
+        new_file.local = local;
+        let file_attr = self.get_attrs(&new_file);
+        match self.files.get_path_mut(dir) {
+            Some(e) => {
+                if e.add(new_file).is_none() {
+                    return Err(error_codes::EEXIST);
+                }
+            }
+            None => return Err(error_codes::ENOENT),
+        }
+        if !local {
+            self.record(&full_path, &Change::Mkdir);
+        }
+        Ok(file_attr)
+    }
+
+    /// Creates the file `name` in `dir` open for writing with `flags`, EEXIST if
+    /// it is there already. The kernel only creates after a lookup found nothing,
+    /// so this is also what makes O_EXCL exclusive.
+    fn create_file(&mut self, dir: &str, name: String, mode: u32, flags: u32) -> Result<FileAttr, c_int> {
+        let full_path = join_path(dir, &name);
+        self.check_protected(&full_path)?;
+        self.check_new(dir, &full_path)?;
+        let local = self.is_local(dir, &full_path);
+        let mut new_file = filesystem_entry::FilesystemEntry::new(
+            FileType::RegularFile,
+            name,
+            dir.to_string(),
+            &mut self.inods,
+            file_mode(mode),
+        );
+        new_file.local = local;
+        new_file.load(&[], self.scratch.as_ref(), &full_path).map_err(|e| io_errno(&e))?;
+        new_file.write = true;
+        new_file.writers = 1;
+        new_file.write_mode = flags;
+        let file_attr = self.get_attrs(&new_file);
+        match self.files.get_path_mut(dir) {
+            Some(e) => {
+                if e.add(new_file).is_none() {
+                    return Err(error_codes::EEXIST);
+                }
+            }
+            None => return Err(error_codes::ENOENT),
+        }
+        if !local {
+            self.record(&full_path, &Change::Dirty);
+            if file_mode(mode) != 0o100644 {
+                self.record(&full_path, &Change::Mode(file_mode(mode)));
+            }
+        }
+        Ok(file_attr)
+    }
+
+    /// Starts timing an operation on `ino` for the metrics and the debug log.
+    fn trace(&self, op: &'static str, ino: u64) -> Trace {
+        Trace::new(op, ino, &self.inods, &self.metrics)
+    }
+
+    /// Contents of the status or stats file.
+    fn control_content(&self, ino: u64) -> String {
+        if ino == control::STATS_INO {
+            metrics::lock(&self.metrics).render()
+        } else {
+            self.status.render()
+        }
+    }
+
+    fn control_attrs(&self, ino: u64) -> FileAttr {
+        let now = ::time::get_time();
+        FileAttr {
+            ino,
+            size: self.control_content(ino).len() as u64,
+            blocks: 1,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            kind: FileType::RegularFile,
+            perm: 0o444 & !self.options.umask,
+            nlink: 1,
+            uid: self.options.uid,
+            gid: self.options.gid,
+            rdev: 0,
+            flags: 0,
+            crtime: self.commit_time,
+        }
+    }
+
+    /// Loads the file at `path` into its write buffer. A file that is open for
+    /// writing already keeps its buffer. O_TRUNC never gets here, the kernel
+    /// truncates through setattr instead.
+    fn open_for_write(&mut self, path: &str, flags: u32) -> Result<(), c_int> {
+        let dirty = {
+            let entry = match self.files.get_path_mut(path) {
+                Some(e) => e,
+                None => return Err(error_codes::ENOENT),
+            };
+            if entry.write {
+                entry.writers += 1;
+                return Ok(());
+            }
+            if entry.local {
+                if self.scratch.is_some() && !entry.spilled {
+                    let content = entry.content.take().unwrap_or_default();
+                    entry.load(&content, self.scratch.as_ref(), path).map_err(|e| io_errno(&e))?;
+                }
+                false
+            } else {
+                let loaded = match entry.oid {
+                    //Copied straight from the blob into the scratch file when there is one
+                    Some(oid) => match self.repository.find_blob(oid) {
+                        Ok(blob) => entry.load(blob.content(), self.scratch.as_ref(), path),
+                        Err(e) => {
+                            //Starting from nothing would throw the old content away on close
+                            error!("Failed to open {}: {}", path, e);
+                            return Err(Error::from(e).errno());
+                        },
+                    },
+                    None => entry.load(&[], self.scratch.as_ref(), path),
+                };
+                loaded.map_err(|e| io_errno(&e))?;
+                //Links are copy-on-write, the other names keep the old content
+                leave_link_group(&mut self.links, entry);
+                true
+            }
+        };
+        if let Some(entry) = self.files.get_path_mut(path) {
+            entry.write = true;
+            entry.writers += 1;
+            entry.write_mode = flags;
+        }
+        if dirty {
+            self.record(path, &Change::Dirty);
+        }
+        Ok(())
+    }
+
+    /// Closes a write handle of the file at `path`. The last one to close stores the
+    /// buffer as a blob and drops it, until then it belongs to the handles still open.
+    fn close_for_write(&mut self, path: &str) -> Result<(), c_int> {
+        let stored = {
+            let entry = match self.files.get_path_mut(path) {
+                Some(e) => e,
+                None => return Err(error_codes::ENOENT),
+            };
+            if !entry.write {
+                return Ok(());
+            }
+            entry.writers = entry.writers.saturating_sub(1);
+            if entry.writers > 0 {
+                return Ok(());
+            }
+            store_buffer(&self.repository, entry, self.scratch.as_ref(), &mut self.quota, &self.metrics, path)?
+        };
+        //Journal the blob before the scratch copy goes away
+        if let Some(oid) = stored {
+            self.record(path, &Change::Blob(oid));
+        }
+        if let Some(entry) = self.files.get_path_mut(path) {
+            if !entry.local {
+                if let Err(e) = entry.clean(self.scratch.as_ref(), path) {
+                    error!("Failed to clean scratch for {}: {}", path, e);
+                }
+            }
+            entry.write = false;
+        }
+        Ok(())
+    }
+
+    /// Changes the mode of the file at `path`, only the owner may do that. Git can
+    /// only tell executable files from the rest, directories always keep their mode.
+    fn chmod(&mut self, req: &Request, path: &str, mode: u32) -> Result<(), c_int> {
+        if req.uid() != 0 && req.uid() != self.options.uid {
+            return Err(error_codes::EPERM);
+        }
+        let changed = {
+            let entry = match self.files.get_path_mut(path) {
+                Some(e) => e,
+                None => return Err(error_codes::ENOENT),
+            };
+            if entry.file_type != FileType::RegularFile || entry.file_mode == file_mode(mode) {
+                return Ok(());
+            }
+            entry.file_mode = file_mode(mode);
+            //The other names keep the old mode, git has no shared inode to change
+            leave_link_group(&mut self.links, entry);
+            !entry.local
+        };
+        if changed {
+            self.record(path, &Change::Mode(file_mode(mode)));
+        }
+        Ok(())
+    }
+
+    /// Sets the size of the file at `path`, cutting it off or zero-filling it.
+    /// Files that are not open for writing get a new blob right away.
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), c_int> {
+        self.quota.check_size(size)?;
+        let stored = {
+            let entry = match self.files.get_path_mut(path) {
+                Some(e) => e,
+                None => return Err(error_codes::ENOENT),
+            };
+            if entry.file_type == FileType::Directory {
+                return Err(error_codes::EISDIR);
+            }
+            if entry.write || entry.local {
+                entry.truncate(size, self.scratch.as_ref(), path).map_err(|e| io_errno(&e))?;
+                entry.size = size;
+                None
+            } else {
+                //Only the part that is kept needs loading
+                let loaded = match entry.oid {
+                    Some(oid) => match self.repository.find_blob(oid) {
+                        Ok(blob) => {
+                            let kept = (size as usize).min(blob.content().len());
+                            entry.load(&blob.content()[..kept], self.scratch.as_ref(), path)
+                        }
+                        Err(e) => {
+                            error!("Failed to truncate {}: {}", path, e);
+                            return Err(Error::from(e).errno());
+                        }
+                    },
+                    None => entry.load(&[], self.scratch.as_ref(), path),
+                };
+                loaded.map_err(|e| io_errno(&e))?;
+                entry.truncate(size, self.scratch.as_ref(), path).map_err(|e| io_errno(&e))?;
+                let stored = store_buffer(&self.repository, entry, self.scratch.as_ref(), &mut self.quota, &self.metrics, path)?;
+                entry.clean(self.scratch.as_ref(), path).map_err(|e| io_errno(&e))?;
+                leave_link_group(&mut self.links, entry);
+                stored
+            }
+        };
+        if let Some(oid) = stored {
+            self.record(path, &Change::Blob(oid));
+        }
+        Ok(())
+    }
+
+    /// Reads up to `size` bytes at `offset` of the file at `path`. Files open for
+    /// writing are read from their buffer, so writes can be read back before close.
+    fn read_at(&mut self, path: &str, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let oid = match self.files.get_path(path) {
+            Some(e) if e.local || e.write => {
+                let data = e.buffer_at(offset as u64, size, self.scratch.as_ref(), path).map_err(|e| io_errno(&e))?;
+                metrics::lock(&self.metrics).bytes_read += data.len() as u64;
+                return Ok(data);
+            }
+            Some(e) => match e.oid {
+                Some(e) => e,
+                //Created but never written
+                None => return Ok(Vec::new()),
+            },
+            None => return Err(error_codes::ENOENT),
+        };
+        let cached = self.read_cache.as_ref().map_or(false, |&(cached, _)| cached == oid);
+        if cached {
+            metrics::lock(&self.metrics).cache_hits += 1;
+        } else {
+            metrics::lock(&self.metrics).cache_misses += 1;
+            let blob = match self.repository.find_blob(oid) {
+                Ok(blob) => blob,
+                Err(e) => {
+                    error!("Failed to read {}: {}", path, e);
+                    return Err(Error::from(e).errno());
+                }
+            };
+            if blob.content().len() > READ_CACHE_MAX {
+                //Too big to keep a copy of, served straight from the object instead
+                let data = slice_at(blob.content(), offset, size).to_vec();
+                metrics::lock(&self.metrics).bytes_read += data.len() as u64;
+                return Ok(data);
+            }
+            self.read_cache = Some((oid, blob.content().to_vec()));
+        }
+        let data = match self.read_cache {
+            Some((_, ref content)) => slice_at(content, offset, size).to_vec(),
+            None => Vec::new(),
+        };
+        metrics::lock(&self.metrics).bytes_read += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Makes the journal durable and commits if `options.commit_on_fsync` is set,
+    /// EIO if that commit fails.
+    fn sync(&mut self) -> Result<(), c_int> {
+        if let Some(ref mut journal) = self.journal {
+            if let Err(e) = journal.sync() {
+                error!("Failed to sync {}: {}", journal.path().display(), e);
+                return Err(io_errno(&e));
+            }
+        }
+        if self.options.commit_on_fsync && self.has_pending_changes() {
+            self.commit();
+            if self.commit_failed.load(Ordering::SeqCst) {
+                return Err(error_codes::EIO);
+            }
+        }
+        Ok(())
+    }
+
+    /// Scans the files that changed since the last commit for secrets, returning
+    /// the ones to hold back mapped to the blob they had in that commit, if any.
+    fn hold_secrets(&mut self) -> HashMap<String, Option<Oid>> {
+        let mut held = HashMap::new();
+        self.status.held.clear();
+        let scanner = match self.scanner {
+            Some(ref s) => s,
+            None => return held,
+        };
+        let head_tree = match self
+            .repository
+            .revparse_single(self.referance)
+            .and_then(|o| o.peel_to_tree())
+        {
+            Ok(tree) => tree,
+            Err(_) => return held,
+        };
+        let mut files = Vec::new();
+        self.files.blobs("", &mut files);
+        for (path, oid) in files {
+            let committed = head_tree.get_path(Path::new(&path)).ok().map(|e| e.id());
+            if committed == Some(oid) {
+                continue;
+            }
+            let blob = match self.repository.find_blob(oid) {
+                Ok(blob) => blob,
+                Err(e) => {
+                    error!("Failed to scan {}: {}", path, e);
+                    continue;
+                }
+            };
+            if let Some(finding) = scanner.scan(blob.content()) {
+                warn!("Holding {} back from the commit, {}", path, finding);
+                self.status.held.push((path.clone(), finding));
+                held.insert(path, committed);
+            }
+        }
+        held
+    }
+
+    /// Commits the mounted tree. If it fails the changes stay pending and `tick`
+    /// tries again, soon if the failure may go away by itself.
+    pub fn commit(&mut self) {
+        self.last_commit_attempt = Instant::now();
+        let e = match self.try_commit() {
+            Ok(()) => {
+                self.commit_failed.store(false, Ordering::SeqCst);
+                return;
             }
-            _ => {}
+            Err(e) => e,
         };
-        file_attr
+        self.commit_retry_delay = if e.is_transient() {
+            COMMIT_RETRY_DELAY
+        } else {
+            COMMIT_RETRY_INTERVAL
+        };
+        match e {
+            Error::Rejected(reason) => {
+                warn!("Commit deferred, {}", reason);
+                self.status.hook_failure = Some(reason);
+            }
+            e => error!("Commit failed, the changes stay pending: {}", e),
+        }
+        self.commit_failed.store(true, Ordering::SeqCst);
     }
 
-    pub fn commit(&mut self) {
-        let new_tree = match self.files.to_git_object(&mut self.repository) {
+    fn try_commit(&mut self) -> error::Result<()> {
+        let held = self.hold_secrets();
+        let placeholder = self.options.placeholder.as_ref().map(String::as_str);
+        let new_tree = match self.files.to_git_object_holding(&mut self.repository, "", &held, placeholder)? {
             Some(nt) => nt,
-            None => panic!("Failed to commit."),
+            None => return Err(Error::Git(git2::Error::from_str("the root is not a tree"))),
         };
-        let tree = self.repository.find_tree(new_tree).unwrap();
+        let committed = {
+        let tree = self.repository.find_tree(new_tree)?;
 
         let last_commit = self
             .repository
-            .revparse_single(self.referance)
-            .unwrap()
-            .peel_to_commit()
-            .unwrap();
-        let sign = Signature::now("git-fs", "git-fs@gitfs.com").unwrap();
-
-        let mut index = match Index::new() {
-            Ok(i) =>i,
-            Err(e) => panic!(e),
-        };
-        match index.read_tree(&tree) {
-            Ok(_) =>{},
-            Err(e) => panic!(e),
-        };
-
-
-        //TODO: Do we update the ref? if not we need to find another way to get "last_commit"
-        match self.repository.commit(
-            Some(self.referance),
-            &sign,
-            &sign,
-            "Automated commit from git-fs",
-            &tree,
-            &[&last_commit],
-        ) {
-            Ok(oid) => {
-                self.repository.set_index(&mut index);
-                println!("Commit complete:{}",oid)
-            },
-            Err(e) => println!("{}", e),
+            .revparse_single(self.referance)?
+            .peel_to_commit()?;
+        //An unchanged tree makes no commit, but the journal is still brought up to date
+        if last_commit.tree_id() == new_tree {
+            if held.is_empty() {
+                debug!("Nothing to commit");
+            } else {
+                info!("Nothing to commit besides held files");
+            }
+            false
+        } else {
+            let sign = Signature::now("git-fs", "git-fs@gitfs.com")?;
+            let mut message = String::from("Automated commit from git-fs");
+            if self.options.run_hooks {
+                if self.hook_checkout.is_none() {
+                    self.hook_checkout = Some(hooks::Checkout::new(&self.repository).map_err(Error::Rejected)?);
+                }
+                if let Some(ref checkout) = self.hook_checkout {
+                    message = checkout.run(new_tree, &message).map_err(Error::Rejected)?;
+                }
+            }
+
+            let mut index = Index::new()?;
+            index.read_tree(&tree)?;
+
+            //TODO: Do we update the ref? if not we need to find another way to get "last_commit"
+            let oid = self.repository.commit(
+                Some(self.referance),
+                &sign,
+                &sign,
+                &message,
+                &tree,
+                &[&last_commit],
+            )?;
+            self.repository.set_index(&mut index);
+            info!("Commit complete: {}", oid);
+            metrics::lock(&self.metrics).commits += 1;
+            self.status.last_commit = Some(oid);
+            self.status.hook_failure = None;
+            true
+        }
         };
+        self.clear_journal();
+        //Held files are still uncommitted, keep them in the journal
+        for path in held.keys() {
+            let oid = self.files.get_path(path).and_then(|e| e.oid);
+            if let Some(oid) = oid {
+                self.record(path, &Change::Blob(oid));
+            }
+        }
+        //So are the writes to files that are still open
+        let mut writing = Vec::new();
+        self.files.writing("", &mut writing);
+        for path in writing {
+            self.record(path.as_str(), &Change::Dirty);
+        }
+        if committed {
+            self.usage.refresh();
+        }
+        if committed && self.options.push_remote.is_some() {
+            self.status.push = Some(PushState::Pending);
+            self.push_attempts = 0;
+            if self.options.push_interval.is_none() {
+                self.push();
+            }
+        }
+        Ok(())
     }
 }
 impl<'collection>  Drop for GitFilesystem<'collection>  {
     fn drop(&mut self) {
-        self.commit();
+        if self.has_pending_changes() {
+            self.commit();
+        }
+        //A push left running would be cut off when the process exits
+        self.wait_for_push();
     }
 }
 
@@ -164,18 +1310,33 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         Ok(())
     }
     fn destroy(&mut self, _req: &Request) {
-        self.commit();
+        if self.has_pending_changes() {
+            self.commit();
+        }
     }
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let trace = self.trace("lookup", parent);
+        self.tick();
+        if parent == FUSE_ROOT_ID {
+            if let Some(ino) = control::lookup(name) {
+                let ttl = Timespec::new(0, 0);
+                reply.entry(&ttl, &self.control_attrs(ino), 0);
+                return;
+            }
+        }
         let mut path = self.inods[parent as usize].clone();
         if !path.is_empty() {
             path = path + "/";
         }
-        path = path + name.to_str().unwrap();
+        match name.to_str() {
+            Some(name) => path = path + name,
+            //Names in the tree are utf-8, so this one can not be there
+            None => return reply.error(trace.fail(error_codes::ENOENT)),
+        }
         let file = match self.files.get_path(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
@@ -183,12 +1344,35 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         let file_attr = self.get_attrs(file);
         reply.entry(&ttl, &file_attr, 0); // TODO: What does generation do?
     }
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let trace = self.trace("access", ino);
+        let result = if control::is_control(ino) {
+            if permitted(req, &self.control_attrs(ino), mask as i32) {
+                Ok(())
+            } else {
+                Err(error_codes::EACCES)
+            }
+        } else {
+            self.check_access(req, &self.inods[ino as usize], mask as i32)
+        };
+        match result {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(trace.fail(e)),
+        }
+    }
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let trace = self.trace("getattr", ino);
+        self.tick();
+        if control::is_control(ino) {
+            let ttl = Timespec::new(0, 0);
+            reply.attr(&ttl, &self.control_attrs(ino));
+            return;
+        }
         let path = &self.inods[ino as usize];
         let file = match self.files.get_path(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
@@ -196,102 +1380,187 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         let file_attr = self.get_attrs(file);
         reply.attr(&ttl, &file_attr);
     }
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let trace = self.trace("setattr", ino);
+        if control::is_control(ino) {
+            return reply.error(trace.fail(error_codes::EACCES));
+        }
+        let path = self.inods[ino as usize].clone();
+        if let Err(e) = self.check_protected(&path) {
+            return reply.error(trace.fail(e));
+        }
+        //TODO: ownership can not be changed yet, times are not tracked
+        if uid.is_some() || gid.is_some() {
+            return reply.error(trace.fail(error_codes::ENOSYS));
+        }
+        if let Some(mode) = mode {
+            if let Err(e) = self.chmod(req, &path, mode) {
+                return reply.error(trace.fail(e));
+            }
+        }
+        if let Some(size) = size {
+            trace.size(size);
+            //Also how O_TRUNC arrives, the open it belongs to already checked access
+            if fh.is_none() {
+                if let Err(e) = self.check_access(req, &path, W_OK) {
+                    return reply.error(trace.fail(e));
+                }
+            }
+            if let Err(e) = self.truncate(&path, size) {
+                return reply.error(trace.fail(e));
+            }
+        }
+        let ttl = Timespec::new(self.ttl, 0);
+        match self.files.get_path(path.as_str()) {
+            Some(entry) => reply.attr(&ttl, &self.get_attrs(entry)),
+            None => reply.error(trace.fail(error_codes::ENOENT)),
+        }
+    }
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        let trace = self.trace("mkdir", parent);
+        if control::shadows(parent, name) {
+            return reply.error(trace.fail(error_codes::EEXIST));
+        }
         let path = self.inods[parent as usize].clone();
+        if let Err(e) = self.check_access(req, path.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
         let name = match name.to_str() {
             Some(s) => s.to_string(),
             None => {
-                reply.error(error_codes::EPERM); // TODO: invalid name error??
-                return;
-            }
-        };
-        let new_file = filesystem_entry::FilesystemEntry::new(
-            FileType::Directory,
-            name,
-            path.to_string(),
-            &mut self.inods,
-            0o040000,
-        );
-        let file_attr = self.get_attrs(&new_file);
-        let file = match self.files.get_path_mut(path.as_str()) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::ENOENT);
-                return;
-            }
-        };
-        match file.add(new_file) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::EEXIST);
+                reply.error(trace.fail(error_codes::EPERM)); // TODO: invalid name error??
                 return;
             }
         };
-        let ttl = Timespec::new(self.ttl, 0);
-        reply.entry(&ttl, &file_attr, 0);
+        match self.make_dir(path.as_str(), name) {
+            Ok(file_attr) => reply.entry(&Timespec::new(self.ttl, 0), &file_attr, 0),
+            Err(e) => reply.error(trace.fail(e)),
+        }
     }
     fn mknod(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
+        mode: u32,
         _rdev: u32,
         reply: ReplyEntry,
     ) {
+        let trace = self.trace("mknod", parent);
+        if control::shadows(parent, name) {
+            return reply.error(trace.fail(error_codes::EEXIST));
+        }
         let path = self.inods[parent as usize].clone();
+        if let Err(e) = self.check_access(req, path.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
         let name = match name.to_str() {
             Some(s) => s.to_string(),
             None => {
-                reply.error(error_codes::EPERM); // TODO: invalid name error??
+                reply.error(trace.fail(error_codes::EPERM)); // TODO: invalid name error??
                 return;
             }
         };
-        let new_file = filesystem_entry::FilesystemEntry::new(
+        if let Err(e) = self.check_protected(&join_path(&path, &name)) {
+            return reply.error(trace.fail(e));
+        }
+        let full_path = join_path(&path, &name);
+        if let Err(e) = self.check_new(path.as_str(), full_path.as_str()) {
+            return reply.error(trace.fail(e));
+        }
+        let local = self.is_local(path.as_str(), &full_path);
+        let mut new_file = filesystem_entry::FilesystemEntry::new(
             FileType::RegularFile,
             name,
             path.to_string(),
             &mut self.inods,
-            33188,
+            file_mode(mode),
         );
+        new_file.local = local;
+        if local {
+            if let Err(e) = new_file.load(&[], self.scratch.as_ref(), full_path.as_str()) {
+                return reply.error(trace.fail(io_errno(&e)));
+            }
+        }
         let file_attr = self.get_attrs(&new_file);
         let file = match self.files.get_path_mut(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
         match file.add(new_file) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::EEXIST);
+                reply.error(trace.fail(error_codes::EEXIST));
                 return;
             }
         };
+        if !local && file_mode(mode) != 0o100644 {
+            self.record(full_path.as_str(), &Change::Mode(file_mode(mode)));
+        }
         let ttl = Timespec::new(self.ttl, 0);
         reply.entry(&ttl, &file_attr, 0);
     }
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let trace = self.trace("rmdir", parent);
         let name = match name.to_str() {
             Some(s) => s,
             None => {
-                reply.error(error_codes::EPERM); // TODO: invalid name error??
+                reply.error(trace.fail(error_codes::EPERM)); // TODO: invalid name error??
                 return;
             }
         };
         let path = self.inods[parent as usize].clone();
+        if let Err(e) = self.check_access(req, path.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
+        if let Err(e) = self.check_protected(&join_path(&path, name)) {
+            return reply.error(trace.fail(e));
+        }
+        let local = match self.files.get_path_mut(&join_path(&path, name)) {
+            Some(dir) => {
+                leave_link_groups(&mut self.links, dir);
+                dir.local
+            }
+            None => false,
+        };
         let file = match self.files.get_path_mut(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
         match file.remove(name, FileType::Directory, &mut self.inods) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e),
+            Err(e) => return reply.error(trace.fail(e)),
         };
+        if !local {
+            self.record(&join_path(&path, name), &Change::Remove);
+        }
+        if let Some(ref scratch) = self.scratch {
+            if let Err(e) = scratch.remove(&join_path(&path, name)) {
+                error!("Failed to remove scratch for {}: {}", join_path(&path, name), e);
+            }
+        }
     }
     fn rename(
         &mut self,
@@ -302,8 +1571,111 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        let old_dir = self.inods[parent as usize].clone();
-        let new_dir = self.inods[newparent as usize].clone();
+        let trace = self.trace("rename", parent);
+        //Protected paths can not be moved away or replaced
+        for &(dir, name) in &[(parent, name), (newparent, newname)] {
+            let path = join_path(&self.inods[dir as usize], &name.to_string_lossy());
+            if let Err(e) = self.check_protected(&path) {
+                return reply.error(trace.fail(e));
+            }
+        }
+        //TODO: rename is not supported yet, this is the EIO fuse answers a dropped reply with
+        reply.error(trace.fail(error_codes::EIO));
+    }
+    fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let trace = self.trace("link", ino);
+        if control::shadows(newparent, newname) {
+            return reply.error(trace.fail(error_codes::EEXIST));
+        }
+        if control::is_control(ino) {
+            return reply.error(trace.fail(error_codes::EPERM));
+        }
+        let path = self.inods[ino as usize].clone();
+        let parent = self.inods[newparent as usize].clone();
+        if let Err(e) = self.check_access(req, parent.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
+        let name = match newname.to_str() {
+            Some(s) => s.to_string(),
+            None => return reply.error(trace.fail(error_codes::EPERM)), // TODO: invalid name error??
+        };
+        let full_path = join_path(&parent, &name);
+        if let Err(e) = self.check_protected(&full_path) {
+            return reply.error(trace.fail(e));
+        }
+        if self.files.get_path(full_path.as_str()).is_some() {
+            return reply.error(trace.fail(error_codes::EEXIST));
+        }
+        //Local files are not in git, so there is no blob to share with a link
+        if self.is_local(parent.as_str(), full_path.as_str()) {
+            return reply.error(trace.fail(error_codes::EXDEV));
+        }
+        let (oid, size, file_mode, group) = {
+            let entry = match self.files.get_path_mut(path.as_str()) {
+                Some(e) => e,
+                None => return reply.error(trace.fail(error_codes::ENOENT)),
+            };
+            if entry.file_type != FileType::RegularFile {
+                return reply.error(trace.fail(error_codes::EPERM));
+            }
+            if entry.local {
+                return reply.error(trace.fail(error_codes::EXDEV));
+            }
+            //Link to what has been written so far, the open file keeps diverging from it
+            if entry.write {
+                if let Err(e) = store_buffer(&self.repository, entry, self.scratch.as_ref(), &mut self.quota, &self.metrics, &path) {
+                    return reply.error(trace.fail(e));
+                }
+            }
+            let oid = match entry.oid {
+                Some(oid) => oid,
+                None => match self.repository.blob(&[]) {
+                    Ok(oid) => oid,
+                    Err(e) => {
+                        error!("Failed to store {}: {}", path, e);
+                        return reply.error(trace.fail(error_codes::EIO));
+                    }
+                },
+            };
+            entry.oid = Some(oid);
+            let group = match entry.link {
+                Some(group) => Some(group),
+                None if entry.write => None,
+                None => {
+                    let group = self.next_link;
+                    self.next_link += 1;
+                    self.links.insert(group, 1);
+                    entry.link = Some(group);
+                    Some(group)
+                }
+            };
+            (oid, entry.size, entry.file_mode, group)
+        };
+        let mut new_file = filesystem_entry::FilesystemEntry::new(
+            FileType::RegularFile,
+            name,
+            parent.clone(),
+            &mut self.inods,
+            file_mode,
+        );
+        new_file.oid = Some(oid);
+        new_file.size = size;
+        new_file.link = group;
+        if let Some(count) = group.and_then(|group| self.links.get_mut(&group)) {
+            *count += 1;
+        }
+        let file_attr = self.get_attrs(&new_file);
+        match self.files.get_path_mut(parent.as_str()) {
+            Some(dir) => {
+                if dir.add(new_file).is_none() {
+                    return reply.error(trace.fail(error_codes::EEXIST));
+                }
+            }
+            None => return reply.error(trace.fail(error_codes::ENOENT)),
+        }
+        self.record(full_path.as_str(), &Change::Blob(oid));
+        let ttl = Timespec::new(self.ttl, 0);
+        reply.entry(&ttl, &file_attr, 0);
     }
     fn readdir(
         &mut self,
@@ -313,25 +1685,35 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        let trace = self.trace("readdir", ino);
+        self.tick();
         let path = &self.inods[ino as usize];
         let folder = match self.files.get_path(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
-        if offset == 0 {
-            reply.add(ino, 0, FileType::Directory, ".");
-            reply.add(ino, 1, FileType::Directory, "..");
-
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_owned()),
+            (ino, FileType::Directory, "..".to_owned()),
+        ];
+        if ino == FUSE_ROOT_ID {
+            entries.push((control::STATUS_INO, FileType::RegularFile, control::STATUS_NAME.to_owned()));
+            entries.push((control::STATS_INO, FileType::RegularFile, control::STATS_NAME.to_owned()));
+        }
+        for file in &folder.children {
+            if control::shadows(ino, OsStr::new(file.name.as_str())) {
+                continue;
+            }
+            entries.push((file.ino as u64, file.file_type, file.name.clone()));
         }
-        for index in (offset as usize)..folder.children.len() {
-            let file = &folder.children[index];
-            let file_type = file.file_type;
-            let file_name = file.name.clone();
-            let fileatr = file.ino as u64;
-            reply.add(fileatr, (index + 2) as i64, file_type, file_name);
+        //The offset of an entry is where a readdir that stops after it resumes
+        for (i, (ino, file_type, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, file_type, name) {
+                break;
+            }
         }
         reply.ok();
     }
@@ -344,176 +1726,183 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         size: u32,
         reply: ReplyData,
     ) {
-        let path = &self.inods[ino as usize];
-        let oid = match self.files.get_path(path.as_str()) {
-            Some(e) => match e.oid {
-                Some(e) => e,
-                None => {
-                    reply.data(&[0u8, 0]);
-                    //reply.error(error_codes::ENOENT);
-                    return;
-                }
-            },
-            None => {
-                reply.error(error_codes::ENOENT);
-                return;
-            }
-        };
-        match self.repository.find_blob(oid) {
-            Ok(blob) => {
-                let (_, content) = blob.content().split_at(offset as usize);
-                if content.len() >= size as usize {
-                    let (content, _) = content.split_at(size as usize);
-                }
-                reply.data(content);
-            }
-            Err(e) => {
-                eprintln!("{}",e);
-                reply.error(error_codes::ENOENT);
-            }
+        let trace = self.trace("read", ino);
+        trace.size(u64::from(size));
+        if control::is_control(ino) {
+            let content = self.control_content(ino).into_bytes();
+            reply.data(slice_at(&content, offset, size));
+            return;
+        }
+        let path = self.inods[ino as usize].clone();
+        match self.read_at(&path, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(trace.fail(e)),
         }
     }
     fn write(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
+        let trace = self.trace("write", ino);
+        trace.size(data.len() as u64);
+        //Permissions were checked when the file was opened, like POSIX does
         let path = &self.inods[ino as usize];
         let offset = offset as usize;
         let entry = match self.files.get_path_mut(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
-        let mut content = match entry.content {
-            Some(ref mut c) => c,
-            None => return reply.error(error_codes::EIO),
+        let len = match entry.buffer_len(self.scratch.as_ref(), path) {
+            Ok(len) => len,
+            Err(e) => return reply.error(trace.fail(io_errno(&e))),
+        };
+        //The file handle holds the open flags, appends go to the end whatever the offset
+        let offset = if fh as u32 & access_codes::O_APPEND != 0 {
+            len as usize
+        } else {
+            offset
         };
-        if content.len() > offset {
-            let tail = &mut content.split_off(offset );
-            content.append(&mut data.to_owned());
-            content.append(tail);
+        if let Err(e) = self.quota.check_size(len.max((offset + data.len()) as u64)) {
+            return reply.error(trace.fail(e));
         }
-        else {
-            content.append(&mut data.to_owned());
+        if let Err(e) = entry.write_at(offset, data, self.scratch.as_ref(), path) {
+            return reply.error(trace.fail(io_errno(&e)));
         }
+        //Keep the size current so the kernel appends at the right place
+        match entry.buffer_len(self.scratch.as_ref(), path) {
+            Ok(len) => entry.size = len,
+            Err(e) => return reply.error(trace.fail(io_errno(&e))),
+        }
+        metrics::lock(&self.metrics).bytes_written += data.len() as u64;
         reply.written(data.len() as u32);
 
     }
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
-        let path = &self.inods[ino as usize];
-        let entry = match self.files.get_path_mut(path.as_str()) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::ENOENT);
-                return;
-            }
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        let trace = self.trace("open", ino);
+        let writing = flags & access_codes::O_ACCMODE != access_codes::O_RDONLY;
+        let mask = match flags & access_codes::O_ACCMODE {
+            m if m == access_codes::O_RDONLY => R_OK,
+            m if m == access_codes::O_WRONLY => W_OK,
+            _ => R_OK | W_OK,
         };
-
-        //Write
-        if flags & access_codes::O_ACCMODE > 0 && !entry.write {
-            if entry.write {
-                reply.error(error_codes::ETXTBSY);
-                return;
+        if control::is_control(ino) {
+            if writing {
+                reply.error(trace.fail(error_codes::EACCES));
+            } else {
+                //The content changes without the file being written, so the page cache is bypassed
+                reply.opened(0, consts::FOPEN_DIRECT_IO);
             }
-            let content = match entry.oid {
-                Some(oid) => match self.repository.find_blob(oid) {
-                    Ok(blob) => blob.content().to_owned(),
-                    Err(e) => {
-                        eprintln!("{}",e);
-                        Vec::new()
-                    },
-                },
-                None => Vec::new(),
-            };
-            entry.content = Some(content);
-            entry.write = true;
-            entry.write_mode = flags;
-            reply.opened(0, flags);
+            return;
+        }
+        let path = self.inods[ino as usize].clone();
+        if let Err(e) = self.check_access(req, &path, mask) {
+            return reply.error(trace.fail(e));
+        }
+        match self.files.get_path_mut(path.as_str()) {
+            Some(entry) => {
+                if entry.local {
+                    if let Err(e) = entry.ensure_buffer(self.scratch.as_ref(), &path) {
+                        return reply.error(trace.fail(io_errno(&e)));
+                    }
+                }
+            }
+            None => return reply.error(trace.fail(error_codes::ENOENT)),
         }
-        //Read only
-        else {
-            reply.opened(0, access_codes::O_RDONLY);
+        if writing {
+            if let Err(e) = self.open_for_write(&path, flags) {
+                return reply.error(trace.fail(e));
+            }
         }
+        //The open flags are kept as the file handle so writes can see O_APPEND
+        reply.opened(u64::from(flags), 0);
     }
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
+        mode: u32,
         flags: u32,
         reply: ReplyCreate
     ) {
+        let trace = self.trace("create", parent);
+        if control::shadows(parent, name) {
+            return reply.error(trace.fail(error_codes::EEXIST));
+        }
         let path = self.inods[parent as usize].clone();
+        if let Err(e) = self.check_access(req, path.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
         let name = match name.to_str() {
             Some(s) => s.to_string(),
             None => {
-                reply.error(error_codes::EPERM); // TODO: invalid name error??
-                return;
-            }
-        };
-        let mut new_file = filesystem_entry::FilesystemEntry::new(
-            FileType::RegularFile,
-            name,
-            path.to_string(),
-            &mut self.inods,
-            33188,
-        );
-        new_file.content = Some(Vec::new());
-        new_file.write = true;
-        new_file.write_mode = flags;
-        let file_attr = self.get_attrs(&new_file);
-        let file = match self.files.get_path_mut(path.as_str()) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::ENOENT);
-                return;
-            }
-        };
-        match file.add(new_file) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::EEXIST);
+                reply.error(trace.fail(error_codes::EPERM)); // TODO: invalid name error??
                 return;
             }
         };
-        let ttl = Timespec::new(self.ttl, 0);
-        reply.created(&ttl, &file_attr, 0,0,flags);
-
+        match self.create_file(path.as_str(), name, mode, flags) {
+            Ok(file_attr) => reply.created(&Timespec::new(self.ttl, 0), &file_attr, 0, u64::from(flags), 0),
+            Err(e) => reply.error(trace.fail(e)),
+        }
     }
     fn unlink(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         reply: ReplyEmpty
     ) {
+        let trace = self.trace("unlink", parent);
         let name = match name.to_str() {
             Some(s) => s,
             None => {
-                reply.error(error_codes::EPERM); // TODO: invalid name error??
+                reply.error(trace.fail(error_codes::EPERM)); // TODO: invalid name error??
                 return;
             }
         };
         let path = self.inods[parent as usize].clone();
+        if let Err(e) = self.check_access(req, path.as_str(), W_OK | X_OK) {
+            return reply.error(trace.fail(e));
+        }
+        if let Err(e) = self.check_protected(&join_path(&path, name)) {
+            return reply.error(trace.fail(e));
+        }
+        let local = self
+            .files
+            .get_path(&join_path(&path, name))
+            .map_or(false, |e| e.local);
+        if let Some(entry) = self.files.get_path_mut(&join_path(&path, name)) {
+            if entry.file_type == FileType::RegularFile {
+                leave_link_group(&mut self.links, entry);
+            }
+        }
         let file = match self.files.get_path_mut(path.as_str()) {
             Some(e) => e,
             None => {
-                reply.error(error_codes::ENOENT);
+                reply.error(trace.fail(error_codes::ENOENT));
                 return;
             }
         };
         match file.remove(name, FileType::RegularFile, &mut self.inods) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e),
+            Err(e) => return reply.error(trace.fail(e)),
+        }
+        if !local {
+            self.record(&join_path(&path, name), &Change::Remove);
+        }
+        if let Some(ref scratch) = self.scratch {
+            if let Err(e) = scratch.remove(&join_path(&path, name)) {
+                error!("Failed to remove scratch for {}: {}", join_path(&path, name), e);
+            }
         }
     }
     fn release(
@@ -521,76 +1910,114 @@ impl<'collection> Filesystem for GitFilesystem<'collection> {
         _req: &Request,
         ino: u64,
         _fh: u64,
-        _flags: u32,
+        flags: u32,
         _lock_owner: u64,
-        flush: bool,
+        _flush: bool,
         reply: ReplyEmpty,
     ) {
-        {
-        let path = &self.inods[ino as usize];
-        let entry = match self.files.get_path_mut(path.as_str()) {
-            Some(e) => e,
-            None => {
-                reply.error(error_codes::ENOENT);
-                return;
-            }
-        };
-        if flush && entry.content.is_some() {
-            let len;
-            match self.repository.blob(match entry.content.as_ref() {
-                Some(ar) => {
-                    len = ar.len();
-                    ar
-                },
-                None => {
-                    return reply.error(error_codes::EIO)
-                },
-            }) {
-                Ok(oid) => {
-                    entry.size = len as u64;
-                    entry.oid = Some(oid);
-                    entry.write = false;
-                }
-                Err(e) => {
-                    return reply.error(error_codes::EIO)
-                },
+        let trace = self.trace("release", ino);
+        let path = self.inods[ino as usize].clone();
+        if flags & access_codes::O_ACCMODE != access_codes::O_RDONLY {
+            if let Err(e) = self.close_for_write(&path) {
+                return reply.error(trace.fail(e));
             }
         }
         reply.ok();
-        }
         self.change_counter += 1;
         if self.change_counter > 10 {
             self.commit();
             self.change_counter = 0;
         }
     }
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let trace = self.trace("fsync", ino);
+        if control::is_control(ino) {
+            return reply.ok();
+        }
+        let path = self.inods[ino as usize].clone();
+        let stored = {
+            let entry = match self.files.get_path_mut(path.as_str()) {
+                Some(e) => e,
+                None => return reply.error(trace.fail(error_codes::ENOENT)),
+            };
+            if !entry.write {
+                None
+            } else {
+                match store_buffer(&self.repository, entry, self.scratch.as_ref(), &mut self.quota, &self.metrics, &path) {
+                    Ok(oid) => oid,
+                    Err(e) => return reply.error(trace.fail(e)),
+                }
+            }
+        };
+        if let Some(oid) = stored {
+            if let Err(e) = sync_object(&self.repository, oid) {
+                error!("Failed to sync object {}: {}", oid, e);
+                return reply.error(trace.fail(io_errno(&e)));
+            }
+            self.record(path.as_str(), &Change::Blob(oid));
+        }
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(trace.fail(e)),
+        }
+    }
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let trace = self.trace("fsyncdir", ino);
+        //Directory changes go to the journal as they happen, it only has to reach the disk
+        match self.sync() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(trace.fail(e)),
+        }
+    }
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let _trace = self.trace("statfs", ino);
+        //Used space is the objects in the repository as last counted, free space is
+        //what the disk holding it has left, or what is left of the quota if that is less
+        let used = match self.usage.get() {
+            Some(objects) => {
+                self.status.objects = Some((objects.count, objects.bytes));
+                objects.bytes
+            }
+            None => 0,
+        };
+        let (mut available, free_inodes) = match usage::disk(self.repository.path()) {
+            Ok(disk) => (disk.available, disk.free_inodes),
+            Err(e) => {
+                error!("Failed to stat {}: {}", self.repository.path().display(), e);
+                (0, 0)
+            }
+        };
+        if let Some(left) = self.quota.remaining() {
+            available = available.min(left);
+        }
+        let free = available / BLOCK_SIZE;
+        let blocks = (used + BLOCK_SIZE - 1) / BLOCK_SIZE + free;
+        let files = self.inods.len() as u64 + free_inodes;
+        reply.statfs(blocks, free, free, files, free_inodes, BLOCK_SIZE as u32, NAME_MAX, BLOCK_SIZE as u32);
+    }
     fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let trace = self.trace("flush", ino);
+        let path = self.inods[ino as usize].clone();
+        let gitignore = path.rsplit('/').next() == Some(".gitignore");
+        let mut stored = None;
         {
-        let path = &self.inods[ino as usize];
+        let path = &path;
         let entry = match self.files.get_path_mut(path.as_str()) {
             Some(e) => e,
-            None => return reply.error(error_codes::ENOENT),
-        };
-        let mut len;
-        let content = match entry.content {
-            Some(ref ar) => {
-                len = ar.len();
-                ar.to_owned()
-            },
-            None => {
-                return reply.error(error_codes::EIO)
-            },
-        };
-        match self.repository.blob(content.as_ref()) {
-            Ok(oid) => {
-                entry.size = len as u64;
-                //entry.content = None;
-                entry.oid = Some(oid);
-            },
-            Err(e) => {
-                return reply.error(error_codes::EIO)
-            },
+            None => return reply.error(trace.fail(error_codes::ENOENT)),
         };
+        if entry.write {
+            stored = match store_buffer(&self.repository, entry, self.scratch.as_ref(), &mut self.quota, &self.metrics, path) {
+                Ok(oid) => oid,
+                Err(e) => return reply.error(trace.fail(e)),
+            };
+        }
+        }
+        if let Some(oid) = stored {
+            self.record(path.as_str(), &Change::Blob(oid));
+        }
+        if gitignore {
+            self.load_ignore_rules();
         }
         self.change_counter += 1;
         if self.change_counter > 10 {
@@ -0,0 +1,85 @@
+use filesystem::protect::Protected;
+use libc;
+use regex::Regex;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Runtime settings for a `GitFilesystem`, filled in from the command line.
+pub struct Options {
+    /// Name of the remote to fetch from, eks: "origin".
+    pub remote: Option<String>,
+    /// How often the remote is fetched and the mounted branch fast-forwarded.
+    pub fetch_interval: Option<Duration>,
+    /// Name of the remote commits are pushed to.
+    pub push_remote: Option<String>,
+    /// Refspec to push, defaults to the mounted branch onto the same name.
+    pub push_refspec: Option<String>,
+    /// Push on this interval instead of after every commit.
+    pub push_interval: Option<Duration>,
+    /// How many times a failed push is retried before giving up until the next commit.
+    pub push_retries: u32,
+    /// Directory dirty file data is kept in instead of memory.
+    pub scratch_dir: Option<PathBuf>,
+    /// What to do with uncommitted changes left in the journal by an earlier mount.
+    pub recovery: Recovery,
+    /// Owner reported for every entry, defaults to the mounting user.
+    pub uid: u32,
+    /// Group reported for every entry, defaults to the group of the mounting user.
+    pub gid: u32,
+    /// Permission bits cleared from every entry.
+    pub umask: u16,
+    /// Paths that may not be written, removed, renamed or have their attributes changed.
+    pub protected: Protected,
+    /// Run the repository's pre-commit and commit-msg hooks before committing.
+    pub run_hooks: bool,
+    /// Files with content matching any of these are kept out of commits.
+    pub secret_rules: Vec<Regex>,
+    /// Files with a token above this many bits of entropy per character are kept out of commits.
+    pub secret_entropy: Option<f64>,
+    /// Largest size a file may be written to, larger writes fail with EFBIG.
+    pub max_file_size: Option<u64>,
+    /// Bytes of new blobs the mount may add to the repository, more fail with ENOSPC.
+    pub object_quota: Option<u64>,
+    /// Commit whenever a file or directory is fsynced.
+    pub commit_on_fsync: bool,
+    /// Empty file committed in empty directories to keep them, eks: ".gitkeep".
+    /// Without it empty directories only live as long as the mount.
+    pub placeholder: Option<String>,
+    /// Local port to serve the metrics on in the Prometheus text format.
+    pub metrics_port: Option<u16>,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            remote: None,
+            fetch_interval: None,
+            push_remote: None,
+            push_refspec: None,
+            push_interval: None,
+            push_retries: 3,
+            scratch_dir: None,
+            recovery: Recovery::Restore,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            umask: 0,
+            protected: Protected::new(Vec::new()),
+            run_hooks: false,
+            secret_rules: Vec::new(),
+            secret_entropy: None,
+            max_file_size: None,
+            object_quota: None,
+            commit_on_fsync: false,
+            placeholder: None,
+            metrics_port: None,
+        }
+    }
+}
+
+pub enum Recovery {
+    /// Put the changes back into the mount, uncommitted.
+    Restore,
+    /// Put the changes back and commit them right away.
+    Commit,
+    /// Throw the changes away.
+    Discard,
+}
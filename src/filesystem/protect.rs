@@ -0,0 +1,83 @@
+use glob::{MatchOptions, Pattern};
+
+/// Glob patterns for paths the mount must never change. Patterns containing a
+/// slash match the whole path from the root of the mount, others match a
+/// single name at any depth, and everything inside a matching directory is
+/// protected with it.
+pub struct Protected {
+    patterns: Vec<Pattern>,
+}
+impl Protected {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        Protected { patterns }
+    }
+    pub fn matches(&self, path: &str) -> bool {
+        if self.patterns.is_empty() || path.is_empty() {
+            return false;
+        }
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        let mut path = path;
+        loop {
+            let name = match path.rfind('/') {
+                Some(i) => &path[i + 1..],
+                None => path,
+            };
+            for pattern in &self.patterns {
+                let subject = if pattern.as_str().contains('/') { path } else { name };
+                if pattern.matches_with(subject, &options) {
+                    return true;
+                }
+            }
+            match path.rfind('/') {
+                Some(i) => path = &path[..i],
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Protected;
+    use glob::Pattern;
+
+    fn protected(patterns: &[&str]) -> Protected {
+        Protected::new(patterns.iter().map(|p| Pattern::new(p).unwrap()).collect())
+    }
+
+    #[test]
+    fn names_match_at_any_depth() {
+        let protected = protected(&["*.lock", ".env"]);
+        assert!(protected.matches("Cargo.lock"));
+        assert!(protected.matches("a/b/yarn.lock"));
+        assert!(protected.matches("config/.env"));
+        assert!(!protected.matches("lock"));
+        assert!(!protected.matches(".envrc"));
+    }
+
+    #[test]
+    fn paths_match_from_the_root() {
+        let protected = protected(&["docs/*.md"]);
+        assert!(protected.matches("docs/README.md"));
+        assert!(!protected.matches("src/docs/README.md"));
+        assert!(!protected.matches("docs/api/index.md"));
+    }
+
+    #[test]
+    fn everything_inside_a_match_is_protected() {
+        let protected = protected(&[".github", "vendor/lib"]);
+        assert!(protected.matches(".github/workflows/ci.yml"));
+        assert!(protected.matches("vendor/lib/a/b.c"));
+        assert!(!protected.matches("vendor/other"));
+    }
+
+    #[test]
+    fn nothing_matches_without_patterns_or_for_the_root() {
+        assert!(!protected(&[]).matches("a"));
+        assert!(!protected(&["*"]).matches(""));
+    }
+}
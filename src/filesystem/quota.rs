@@ -0,0 +1,105 @@
+use filesystem::error_codes;
+use git2::{Oid, Repository};
+use std::os::raw::c_int;
+
+/// Limits on how much a mount may grow files and the repository.
+pub struct Quota {
+    max_file_size: Option<u64>,
+    object_bytes: Option<u64>,
+    used: u64,
+}
+impl Quota {
+    pub fn new(max_file_size: Option<u64>, object_bytes: Option<u64>) -> Self {
+        Quota {
+            max_file_size,
+            object_bytes,
+            used: 0,
+        }
+    }
+    /// Checks that a file may grow to `len` bytes, EFBIG if it is too large
+    /// and ENOSPC if storing it would not fit in what is left of the budget.
+    pub fn check_size(&self, len: u64) -> Result<(), c_int> {
+        if self.max_file_size.map_or(false, |max| len > max) {
+            return Err(error_codes::EFBIG);
+        }
+        if self.remaining().map_or(false, |left| len > left) {
+            return Err(error_codes::ENOSPC);
+        }
+        Ok(())
+    }
+    /// Checks that a blob `oid` of `len` bytes fits in the budget, ENOSPC if it
+    /// does not. Returns what it costs, nothing if it is in the repository already.
+    pub fn check_blob(&self, repository: &Repository, oid: Oid, len: u64) -> Result<u64, c_int> {
+        let left = match self.remaining() {
+            Some(left) => left,
+            None => return Ok(0),
+        };
+        let exists = match repository.odb() {
+            Ok(odb) => odb.exists(oid),
+            Err(_) => false,
+        };
+        if exists {
+            return Ok(0);
+        }
+        if len > left {
+            return Err(error_codes::ENOSPC);
+        }
+        Ok(len)
+    }
+    /// Counts what `check_blob` said a blob costs against the budget, once it is written.
+    pub fn charge(&mut self, cost: u64) {
+        self.used += cost;
+    }
+    /// Bytes of new objects this session may still write, None if unlimited.
+    pub fn remaining(&self) -> Option<u64> {
+        self.object_bytes.map(|max| max.saturating_sub(self.used))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quota;
+    use filesystem::error_codes;
+    use git2::{ObjectType, Oid, Repository};
+    use tempdir::TempDir;
+
+    #[test]
+    fn unlimited_by_default() {
+        let quota = Quota::new(None, None);
+        assert_eq!(quota.check_size(u64::max_value()), Ok(()));
+        assert_eq!(quota.remaining(), None);
+    }
+
+    #[test]
+    fn files_may_not_grow_past_the_largest_size() {
+        let quota = Quota::new(Some(10), None);
+        assert_eq!(quota.check_size(10), Ok(()));
+        assert_eq!(quota.check_size(11), Err(error_codes::EFBIG));
+    }
+
+    #[test]
+    fn new_blobs_use_up_the_budget() {
+        let dir = TempDir::new("gitfs-quota").unwrap();
+        let repository = Repository::init_bare(dir.path()).unwrap();
+        let mut quota = Quota::new(None, Some(10));
+        let oid = Oid::hash_object(ObjectType::Blob, b"123456").unwrap();
+        assert_eq!(quota.check_blob(&repository, oid, 6), Ok(6));
+        //Nothing is used up until the blob is written
+        assert_eq!(quota.remaining(), Some(10));
+        quota.charge(6);
+        assert_eq!(quota.remaining(), Some(4));
+        assert_eq!(quota.check_size(5), Err(error_codes::ENOSPC));
+        let oid = Oid::hash_object(ObjectType::Blob, b"12345").unwrap();
+        assert_eq!(quota.check_blob(&repository, oid, 5), Err(error_codes::ENOSPC));
+        assert_eq!(quota.remaining(), Some(4));
+    }
+
+    #[test]
+    fn blobs_already_in_the_repository_are_free() {
+        let dir = TempDir::new("gitfs-quota").unwrap();
+        let repository = Repository::init_bare(dir.path()).unwrap();
+        let oid = repository.blob(b"0123456789abcdef").unwrap();
+        let quota = Quota::new(None, Some(10));
+        assert_eq!(quota.check_blob(&repository, oid, 16), Ok(0));
+    }
+}
@@ -0,0 +1,137 @@
+use filesystem::control::PushState;
+use git2::{self, Config, Cred, CredentialType, FetchOptions, Oid, PushOptions, RemoteCallbacks, Repository};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How many times credentials are offered to a remote, libgit2 keeps asking for as long as it gets some.
+static CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Callbacks that authenticate the way git does, with the ssh agent for ssh
+/// remotes and the credential helpers in `config` for http remotes.
+pub fn callbacks<'a>(config: Option<Config>) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username, allowed| {
+        attempts += 1;
+        if attempts > CREDENTIAL_ATTEMPTS {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(ref config) = config {
+                return Cred::credential_helper(config, url, username);
+            }
+        }
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+        Err(git2::Error::from_str("no supported credentials"))
+    });
+    callbacks
+}
+
+/// What to fetch, the mounted branch from a remote into its tracking ref.
+#[derive(Clone)]
+pub struct Fetch {
+    pub remote: String,
+    /// Full name of the mounted branch, eks: "refs/heads/master".
+    pub branch: String,
+    /// Full name of the tracking ref, eks: "refs/remotes/origin/master".
+    pub tracking: String,
+}
+impl Fetch {
+    /// Fetches into the tracking ref and returns true if it is now ahead of the branch.
+    pub fn run(&self, repository: &Repository) -> Result<bool, git2::Error> {
+        let refspec = format!("{}:{}", self.branch, self.tracking);
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks(repository.config().ok()));
+        repository
+            .find_remote(self.remote.as_str())?
+            .fetch(&[refspec.as_str()], Some(&mut fetch_options), None)?;
+        let (local, remote) = self.heads(repository)?;
+        Ok(remote != local && repository.graph_descendant_of(remote, local)?)
+    }
+    /// The commits the branch and the tracking ref point at.
+    pub fn heads(&self, repository: &Repository) -> Result<(Oid, Oid), git2::Error> {
+        let local = repository.refname_to_id(self.branch.as_str())?;
+        let remote = repository.refname_to_id(self.tracking.as_str())?;
+        Ok((local, remote))
+    }
+}
+
+/// Runs `fetch` every `interval` from a thread with its own handle on the
+/// repository at `git_dir`, setting `behind` whenever the branch can be fast-forwarded.
+pub fn fetch_periodically(git_dir: PathBuf, fetch: Fetch, interval: Duration, behind: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let repository = match Repository::open(&git_dir) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to open {} for fetching: {}", git_dir.display(), e);
+                return;
+            }
+        };
+        loop {
+            thread::sleep(interval);
+            match fetch.run(&repository) {
+                Ok(true) => behind.store(true, Ordering::SeqCst),
+                Ok(false) => {}
+                Err(e) => warn!("Fetch from {} failed: {}", fetch.remote, e),
+            }
+        }
+    });
+}
+
+/// What to push, a refspec to a remote.
+pub struct Push {
+    pub remote: String,
+    pub refspec: String,
+}
+impl Push {
+    /// Pushes, telling a remote that refused the update apart from a push that did not get through.
+    pub fn run(&self, repository: &Repository) -> Result<(), PushState> {
+        let mut remote = repository
+            .find_remote(self.remote.as_str())
+            .map_err(|e| PushState::Failed(e.to_string()))?;
+        let mut rejected = None;
+        let result = {
+            let mut callbacks = callbacks(repository.config().ok());
+            callbacks.push_update_reference(|name, status| {
+                if let Some(status) = status {
+                    rejected = Some(format!("{}: {}", name, status));
+                }
+                Ok(())
+            });
+            let mut push_options = PushOptions::new();
+            push_options.remote_callbacks(callbacks);
+            remote.push(&[self.refspec.as_str()], Some(&mut push_options))
+        };
+        match (result, rejected) {
+            (_, Some(e)) => Err(PushState::Rejected(e)),
+            (Err(ref e), None) if e.code() == git2::ErrorCode::NotFastForward => {
+                Err(PushState::Rejected(e.to_string()))
+            }
+            (Err(e), None) => Err(PushState::Failed(e.to_string())),
+            (Ok(_), None) => Ok(()),
+        }
+    }
+}
+
+/// Runs `push` of the commit `oid` from a thread with its own handle on the
+/// repository at `git_dir`, the returned receiver gets the commit and the outcome.
+pub fn push_in_background(git_dir: PathBuf, push: Push, oid: Oid) -> Receiver<(Oid, Result<(), PushState>)> {
+    let (done, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = match Repository::open(&git_dir) {
+            Ok(repository) => push.run(&repository),
+            Err(e) => Err(PushState::Failed(e.to_string())),
+        };
+        let _ = done.send((oid, result));
+    });
+    receiver
+}
@@ -0,0 +1,110 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory on disk holding dirty file data, laid out like the mounted tree
+/// so uncommitted work can be found by hand if the mount goes away.
+pub struct Scratch {
+    root: PathBuf,
+}
+impl Scratch {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Scratch {
+            root: fs::canonicalize(root)?,
+        })
+    }
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+    fn open(&self, path: &str) -> io::Result<File> {
+        let path = self.path(path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+    }
+    /// Replaces the content stored for `path`.
+    pub fn store(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut file = self.open(path)?;
+        file.set_len(0)?;
+        file.write_all(data)
+    }
+    pub fn write_at(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = self.open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+    pub fn truncate(&self, path: &str, len: u64) -> io::Result<()> {
+        self.open(path)?.set_len(len)
+    }
+    pub fn read_at(&self, path: &str, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(path))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        file.take(u64::from(size)).read_to_end(&mut data)?;
+        Ok(data)
+    }
+    pub fn len(&self, path: &str) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(path))?.len())
+    }
+    /// Removes whatever is stored for `path`, files and directories alike.
+    pub fn remove(&self, path: &str) -> io::Result<()> {
+        let path = self.path(path);
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scratch;
+    use tempdir::TempDir;
+
+    #[test]
+    fn stores_and_reads_back() {
+        let dir = TempDir::new("gitfs-scratch").unwrap();
+        let scratch = Scratch::new(dir.path()).unwrap();
+        scratch.store("a/b/file", b"hello world").unwrap();
+        assert!(dir.path().join("a/b/file").is_file());
+        assert_eq!(scratch.len("a/b/file").unwrap(), 11);
+        assert_eq!(scratch.read_at("a/b/file", 6, 100).unwrap(), b"world");
+        scratch.store("a/b/file", b"bye").unwrap();
+        assert_eq!(scratch.read_at("a/b/file", 0, 100).unwrap(), b"bye");
+    }
+
+    #[test]
+    fn writes_past_the_end_leave_a_zero_filled_gap() {
+        let dir = TempDir::new("gitfs-scratch").unwrap();
+        let scratch = Scratch::new(dir.path()).unwrap();
+        scratch.store("file", b"ab").unwrap();
+        scratch.write_at("file", 4, b"cd").unwrap();
+        assert_eq!(scratch.read_at("file", 0, 100).unwrap(), b"ab\0\0cd");
+        scratch.truncate("file", 1).unwrap();
+        assert_eq!(scratch.read_at("file", 0, 100).unwrap(), b"a");
+    }
+
+    #[test]
+    fn removes_files_and_directories() {
+        let dir = TempDir::new("gitfs-scratch").unwrap();
+        let scratch = Scratch::new(dir.path()).unwrap();
+        scratch.store("a/b/file", b"x").unwrap();
+        scratch.store("c", b"x").unwrap();
+        scratch.remove("a").unwrap();
+        scratch.remove("c").unwrap();
+        scratch.remove("missing").unwrap();
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("c").exists());
+    }
+}
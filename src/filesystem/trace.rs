@@ -0,0 +1,66 @@
+use filesystem::metrics::{self, Metrics};
+use logging::{self, Level};
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Times one filesystem operation and counts it in the metrics when it is dropped,
+/// with debug logging on it is also logged with the errno it replied with.
+pub struct Trace {
+    op: &'static str,
+    ino: u64,
+    /// Only looked up when the operation is going to be logged.
+    path: Option<String>,
+    size: Cell<Option<u64>>,
+    errno: Cell<c_int>,
+    start: Instant,
+    metrics: Arc<Mutex<Metrics>>,
+}
+impl Trace {
+    pub fn new(op: &'static str, ino: u64, inods: &[String], metrics: &Arc<Mutex<Metrics>>) -> Trace {
+        let path = if logging::enabled(Level::Debug) {
+            inods.get(ino as usize).cloned()
+        } else {
+            None
+        };
+        Trace {
+            op,
+            ino,
+            path,
+            size: Cell::new(None),
+            errno: Cell::new(0),
+            start: Instant::now(),
+            metrics: metrics.clone(),
+        }
+    }
+    pub fn size(&self, size: u64) {
+        self.size.set(Some(size));
+    }
+    /// Records `errno` as the reply and passes it on.
+    pub fn fail(&self, errno: c_int) -> c_int {
+        self.errno.set(errno);
+        errno
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        metrics::lock(&self.metrics).record(self.op, micros, self.errno.get());
+        if !logging::enabled(Level::Debug) {
+            return;
+        }
+        let mut fields = vec![("op", self.op.to_owned()), ("ino", self.ino.to_string())];
+        if let Some(ref path) = self.path {
+            fields.push(("path", if path.is_empty() { "/".to_owned() } else { path.clone() }));
+        }
+        if let Some(size) = self.size.get() {
+            fields.push(("size", size.to_string()));
+        }
+        fields.push(("latency_us", micros.to_string()));
+        fields.push(("errno", self.errno.get().to_string()));
+        logging::write(Level::Debug, self.op, &fields);
+    }
+}
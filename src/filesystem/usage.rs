@@ -0,0 +1,108 @@
+use git2::Repository;
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many objects a repository holds and their total size.
+#[derive(Clone, Copy)]
+pub struct Objects {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+pub fn objects(repository: &Repository) -> Result<Objects, git2::Error> {
+    let odb = repository.odb()?;
+    let mut ids = Vec::new();
+    odb.foreach(|oid| {
+        ids.push(*oid);
+        true
+    })?;
+    let mut bytes = 0;
+    for oid in &ids {
+        bytes += odb.read_header(*oid)?.0 as u64;
+    }
+    Ok(Objects {
+        count: ids.len() as u64,
+        bytes,
+    })
+}
+
+/// The object count of a repository, kept up to date from a thread of its own
+/// since going through a large object database takes a while.
+pub struct ObjectUsage {
+    git_dir: PathBuf,
+    state: Arc<Mutex<CountState>>,
+}
+#[derive(Default)]
+struct CountState {
+    objects: Option<Objects>,
+    running: bool,
+    /// Objects were added while counting, so the count runs again.
+    stale: bool,
+}
+impl ObjectUsage {
+    pub fn new(git_dir: &Path) -> Self {
+        ObjectUsage {
+            git_dir: git_dir.to_owned(),
+            state: Arc::new(Mutex::new(CountState::default())),
+        }
+    }
+    /// Counts the objects again in the background, called when objects were added.
+    pub fn refresh(&self) {
+        {
+            let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if state.running {
+                state.stale = true;
+                return;
+            }
+            state.running = true;
+        }
+        let git_dir = self.git_dir.clone();
+        let state = self.state.clone();
+        thread::spawn(move || loop {
+            let counted = Repository::open(&git_dir).and_then(|repository| objects(&repository));
+            let mut state = state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match counted {
+                Ok(objects) => state.objects = Some(objects),
+                Err(e) => error!("Failed to count objects: {}", e),
+            }
+            if !state.stale {
+                state.running = false;
+                return;
+            }
+            state.stale = false;
+        });
+    }
+    /// The last count, None until the first one is done.
+    pub fn get(&self) -> Option<Objects> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).objects
+    }
+}
+
+/// Free space on the filesystem holding `path`.
+pub struct Disk {
+    /// Bytes an unprivileged user may still write.
+    pub available: u64,
+    /// Inodes that are still free.
+    pub free_inodes: u64,
+}
+
+pub fn disk(path: &Path) -> io::Result<Disk> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Disk {
+            available: stat.f_bavail as u64 * stat.f_frsize as u64,
+            free_inodes: stat.f_favail as u64,
+        })
+    }
+}
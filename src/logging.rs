@@ -0,0 +1,118 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use time;
+
+/// How much to log, each level includes the ones above it.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    /// Also traces every filesystem operation.
+    Debug = 3,
+}
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+impl FromStr for Level {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Sets the level to log at and whether lines are written as JSON objects.
+pub fn init(level: Level, json: bool) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes one line to stderr, `fields` are extra key value pairs.
+pub fn write(level: Level, message: &str, fields: &[(&str, String)]) {
+    if !enabled(level) {
+        return;
+    }
+    let now = time::now_utc();
+    let mut line = String::new();
+    if JSON.load(Ordering::Relaxed) {
+        let _ = write!(
+            line,
+            "{{\"time\":\"{}\",\"level\":\"{}\",\"msg\":{}",
+            now.rfc3339(),
+            level.name(),
+            quote(message)
+        );
+        for &(key, ref value) in fields {
+            let _ = write!(line, ",{}:{}", quote(key), quote(value));
+        }
+        line.push('}');
+    } else {
+        let _ = write!(line, "{} {:5} {}", now.rfc3339(), level.name().to_uppercase(), message);
+        for &(key, ref value) in fields {
+            let _ = write!(line, " {}={}", key, value);
+        }
+    }
+    line.push('\n');
+    let stderr = io::stderr();
+    let _ = stderr.lock().write_all(line.as_bytes());
+}
+
+/// `s` as a JSON string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[allow(unused_macros)]
+macro_rules! error {
+    ($($arg:tt)*) => { ::logging::write(::logging::Level::Error, &format!($($arg)*), &[]) };
+}
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => { ::logging::write(::logging::Level::Warn, &format!($($arg)*), &[]) };
+}
+#[allow(unused_macros)]
+macro_rules! info {
+    ($($arg:tt)*) => { ::logging::write(::logging::Level::Info, &format!($($arg)*), &[]) };
+}
+#[allow(unused_macros)]
+macro_rules! debug {
+    ($($arg:tt)*) => { ::logging::write(::logging::Level::Debug, &format!($($arg)*), &[]) };
+}
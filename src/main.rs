@@ -4,17 +4,73 @@
 extern crate clap;
 extern crate fuse;
 extern crate git2;
+extern crate glob;
 extern crate libc;
+extern crate regex;
+extern crate tempdir;
 extern crate time;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use glob::Pattern;
+use regex::Regex;
 
+#[macro_use]
+mod logging;
+
+mod daemon;
 mod filesystem;
-use std::path::Path;
+mod shutdown;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Parses the value of a numeric argument, exiting with a message if it is invalid.
+fn parse_arg<T: FromStr>(args: &ArgMatches, name: &str) -> Option<T> {
+    match args.value_of(name) {
+        Some(s) => match s.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                error!("Invalid {}: {}", name.to_lowercase(), s);
+                process::exit(1);
+            }
+        },
+        None => None,
+    }
+}
+
+/// Logs that starting failed and exits, a daemon also reports it to the process waiting for it to start.
+fn fail_start(ready: Option<daemon::Ready>, message: &str) -> ! {
+    error!("{}", message);
+    if let Some(ready) = ready {
+        ready.fail(message);
+    }
+    process::exit(1)
+}
+
+/// Name of the repository at `path`, the name of its directory without any .git.
+fn repository_name(path: &str) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let mut name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+    if name.as_ref().map_or(false, |n| n == ".git") {
+        name = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned());
+    }
+    match name {
+        Some(n) => n.trim_end_matches(".git").replace(',', "_"),
+        None => "gitfuse-rs".to_owned(),
+    }
+}
 
 fn main() {
     let args = App::new("git filesystem")
         .version("0.1.0")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("Repository path")
                 .short("g")
@@ -38,16 +94,371 @@ fn main() {
                 .help("The path to where the filesystem will mount")
                 .takes_value(true)
                 .required(true),
+        ).arg(
+            Arg::with_name("Remote")
+                .short("r")
+                .long("remote")
+                .value_name("NAME")
+                .help("Remote to fetch the mounted branch from eks: \"origin\"")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Fetch interval")
+                .long("fetch_interval")
+                .value_name("SECONDS")
+                .help("How often to fetch from the remote and fast-forward the mount")
+                .takes_value(true)
+                .requires("Remote"),
+        ).arg(
+            Arg::with_name("Push remote")
+                .long("push_remote")
+                .value_name("NAME")
+                .help("Remote to push commits to")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Push refspec")
+                .long("push_refspec")
+                .value_name("REFSPEC")
+                .help("Refspec to push, defaults to the mounted branch")
+                .takes_value(true)
+                .requires("Push remote"),
+        ).arg(
+            Arg::with_name("Push interval")
+                .long("push_interval")
+                .value_name("SECONDS")
+                .help("Push on an interval instead of after every commit")
+                .takes_value(true)
+                .requires("Push remote"),
+        ).arg(
+            Arg::with_name("Push retries")
+                .long("push_retries")
+                .value_name("COUNT")
+                .help("How many times a failed push is retried, default 3")
+                .takes_value(true)
+                .requires("Push remote"),
+        ).arg(
+            Arg::with_name("Scratch directory")
+                .long("scratch_dir")
+                .value_name("PATH")
+                .help("Directory to keep uncommitted file data in instead of memory")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Recover")
+                .long("recover")
+                .value_name("ACTION")
+                .help("What to do with uncommitted changes left by a crashed mount, default restore")
+                .takes_value(true)
+                .possible_values(&["restore", "commit", "discard"]),
+        ).arg(
+            Arg::with_name("Daemon")
+                .short("d")
+                .long("daemon")
+                .help("Detach from the terminal and run in the background")
+                .conflicts_with("Foreground"),
+        ).arg(
+            Arg::with_name("Foreground")
+                .short("f")
+                .long("foreground")
+                .help("Stay in the foreground, this is the default"),
+        ).arg(
+            Arg::with_name("Hooks")
+                .long("hooks")
+                .help("Run the repository's pre-commit and commit-msg hooks before each commit"),
+        ).arg(
+            Arg::with_name("Scan secrets")
+                .long("scan_secrets")
+                .help("Keep files that look like they hold keys or tokens out of commits"),
+        ).arg(
+            Arg::with_name("Fsync commit")
+                .long("fsync_commit")
+                .help("Commit whenever a file or directory is fsynced"),
+        ).arg(
+            Arg::with_name("Pidfile")
+                .long("pidfile")
+                .value_name("PATH")
+                .help("Where to write the process id, daemons default to one per mount point")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Log file")
+                .long("log_file")
+                .value_name("PATH")
+                .help("File a daemon writes its output to, discarded if not given")
+                .takes_value(true)
+                .requires("Daemon"),
+        ).arg(
+            Arg::with_name("Log level")
+                .long("log_level")
+                .value_name("LEVEL")
+                .help("How much to log, default info, debug also traces every filesystem operation")
+                .takes_value(true)
+                .possible_values(&["error", "warn", "info", "debug"]),
+        ).arg(
+            Arg::with_name("Log json")
+                .long("log_json")
+                .help("Write log lines as JSON objects"),
+        ).arg(
+            Arg::with_name("Mount options")
+                .short("o")
+                .value_name("OPTIONS")
+                .help("Comma separated FUSE mount options eks: \"allow_other,ro,max_read=131072\"")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        ).arg(
+            Arg::with_name("Uid")
+                .long("uid")
+                .value_name("UID")
+                .help("Owner of every file, defaults to the mounting user")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Gid")
+                .long("gid")
+                .value_name("GID")
+                .help("Group of every file, defaults to the mounting user's group")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Umask")
+                .long("umask")
+                .value_name("OCTAL")
+                .help("Permission bits to clear from every file eks: \"022\"")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Protect")
+                .long("protect")
+                .value_name("GLOB")
+                .help("Path pattern the mount may not change eks: \".github/**\",\"*.lock\"")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        ).arg(
+            Arg::with_name("Secret rule")
+                .long("secret_rule")
+                .value_name("REGEX")
+                .help("Keep files with content matching this out of commits")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        ).arg(
+            Arg::with_name("Secret entropy")
+                .long("secret_entropy")
+                .value_name("BITS")
+                .help("Keep files with a token above this entropy per character out of commits, default 4.5 with --scan_secrets")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Placeholder")
+                .long("placeholder")
+                .value_name("NAME")
+                .help("Empty file to commit in empty directories to keep them eks: \".gitkeep\"")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Max file size")
+                .long("max_file_size")
+                .value_name("BYTES")
+                .help("Largest size a file may be written to")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Object quota")
+                .long("object_quota")
+                .value_name("BYTES")
+                .help("Bytes of new objects the mount may add to the repository")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("Metrics port")
+                .long("metrics_port")
+                .value_name("PORT")
+                .help("Serve metrics in the Prometheus text format on this port of 127.0.0.1")
+                .takes_value(true),
+        ).subcommand(
+            SubCommand::with_name("unmount")
+                .about("Stops the daemon serving a mount point, committing pending changes")
+                .arg(
+                    Arg::with_name("Mount point")
+                        .value_name("PATH")
+                        .help("The mount point to unmount")
+                        .required(true),
+                ).arg(
+                    Arg::with_name("Pidfile")
+                        .long("pidfile")
+                        .value_name("PATH")
+                        .help("Pidfile the daemon was started with")
+                        .takes_value(true),
+                ),
         ).get_matches();
 
-    let path = args.value_of("Repository path").unwrap();
+    //Invalid arguments are logged too, an invalid level at the default one
+    logging::init(logging::Level::Info, args.is_present("Log json"));
+    if let Some(level) = parse_arg(&args, "Log level") {
+        logging::init(level, args.is_present("Log json"));
+    }
+
+    if let Some(args) = args.subcommand_matches("unmount") {
+        let mount_point = Path::new(args.value_of("Mount point").unwrap());
+        let pidfile = match args.value_of("Pidfile") {
+            Some(p) => PathBuf::from(p),
+            None => daemon::default_pidfile(mount_point),
+        };
+        if let Err(e) = daemon::stop(&pidfile) {
+            error!("Failed to stop daemon from {}: {}", pidfile.display(), e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    //A daemon runs from "/", so every path is made absolute before it starts
+    let path = daemon::absolute(Path::new(args.value_of("Repository path").unwrap()));
+    let path = &*path.to_string_lossy();
     let git_tag = args.value_of("Git tag").unwrap_or("HEAD");
     let mount_point = args.value_of("Mount point").unwrap();
 
+    let mut options = filesystem::Options::default();
+    options.remote = args.value_of("Remote").map(|r| r.to_owned());
+    options.fetch_interval = parse_arg(&args, "Fetch interval").map(Duration::from_secs);
+    options.push_remote = args.value_of("Push remote").map(|r| r.to_owned());
+    options.push_refspec = args.value_of("Push refspec").map(|r| r.to_owned());
+    options.push_interval = parse_arg(&args, "Push interval").map(Duration::from_secs);
+    if let Some(retries) = parse_arg(&args, "Push retries") {
+        options.push_retries = retries;
+    }
+    options.run_hooks = args.is_present("Hooks");
+    options.commit_on_fsync = args.is_present("Fsync commit");
+    options.scratch_dir = args
+        .value_of("Scratch directory")
+        .map(|dir| daemon::absolute(Path::new(dir)));
+    options.recovery = match args.value_of("Recover") {
+        Some("commit") => filesystem::Recovery::Commit,
+        Some("discard") => filesystem::Recovery::Discard,
+        _ => filesystem::Recovery::Restore,
+    };
+    if let Some(uid) = parse_arg(&args, "Uid") {
+        options.uid = uid;
+    }
+    if let Some(gid) = parse_arg(&args, "Gid") {
+        options.gid = gid;
+    }
+    if let Some(umask) = args.value_of("Umask") {
+        options.umask = match u16::from_str_radix(umask, 8) {
+            Ok(umask) if umask <= 0o777 => umask,
+            _ => {
+                error!("Invalid umask: {}", umask);
+                process::exit(1);
+            }
+        };
+    }
+
+    if let Some(globs) = args.values_of("Protect") {
+        let mut patterns = Vec::new();
+        for glob in globs {
+            match Pattern::new(glob) {
+                Ok(pattern) => patterns.push(pattern),
+                Err(e) => {
+                    error!("Invalid protect pattern {}: {}", glob, e);
+                    process::exit(1);
+                }
+            }
+        }
+        options.protected = filesystem::Protected::new(patterns);
+    }
+
+    if args.is_present("Scan secrets") {
+        options.secret_rules = filesystem::default_secret_rules();
+        options.secret_entropy = Some(4.5);
+    }
+    if let Some(rules) = args.values_of("Secret rule") {
+        for rule in rules {
+            match Regex::new(rule) {
+                Ok(regex) => options.secret_rules.push(regex),
+                Err(e) => {
+                    error!("Invalid secret rule {}: {}", rule, e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    if let Some(entropy) = parse_arg(&args, "Secret entropy") {
+        options.secret_entropy = Some(entropy);
+    }
+    if let Some(name) = args.value_of("Placeholder") {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            error!("Invalid placeholder name: {}", name);
+            process::exit(1);
+        }
+        options.placeholder = Some(name.to_owned());
+    }
+    options.max_file_size = parse_arg(&args, "Max file size");
+    options.object_quota = parse_arg(&args, "Object quota");
+    options.metrics_port = parse_arg(&args, "Metrics port");
+
+    let mount_point = match fs::canonicalize(mount_point) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Invalid mount point {}: {}", mount_point, e);
+            process::exit(1);
+        }
+    };
+    let daemonize = args.is_present("Daemon");
+    let pidfile = match args.value_of("Pidfile") {
+        Some(p) => Some(daemon::absolute(Path::new(p))),
+        None if daemonize => Some(daemon::default_pidfile(&mount_point)),
+        None => None,
+    };
+
+    let mut mount_options: Vec<String> = match args.values_of("Mount options") {
+        Some(values) => values
+            .flat_map(|v| v.split(','))
+            .filter(|o| !o.is_empty())
+            .map(|o| o.to_owned())
+            .collect(),
+        None => Vec::new(),
+    };
+    //Lets `mount` show which repository is mounted where
+    if !mount_options.iter().any(|o| o.starts_with("fsname=")) {
+        mount_options.push(format!("fsname={}", repository_name(path)));
+    }
+    if !mount_options.iter().any(|o| o.starts_with("subtype=")) {
+        mount_options.push("subtype=gitfuse-rs".to_owned());
+    }
+    let mount_options = [OsString::from("-o"), OsString::from(mount_options.join(","))];
+    let mount_options: Vec<&OsStr> = mount_options.iter().map(|o| o.as_os_str()).collect();
+
+    let mut ready = None;
+    if daemonize {
+        match daemon::daemonize(args.value_of("Log file").map(Path::new)) {
+            Ok(r) => ready = Some(r),
+            Err(e) => fail_start(None, &format!("Failed to daemonize: {}", e)),
+        }
+    }
+    let _pidfile_lock = match pidfile {
+        Some(ref pidfile) => match daemon::write_pidfile(pidfile) {
+            Ok(file) => Some(file),
+            Err(e) => fail_start(ready, &format!("Failed to write {}: {}", pidfile.display(), e)),
+        },
+        None => None,
+    };
+    let commit_failed;
     {
-        let filesys = filesystem::GitFilesystem::new(path, git_tag);
-        let path = Path::new(mount_point);
-        fuse::mount(filesys, &path, &[]).unwrap();
+        //Opened after daemonizing, the threads it starts would not survive the fork
+        let filesys = match filesystem::GitFilesystem::new(path, git_tag, options) {
+            Ok(filesys) => filesys,
+            Err(e) => fail_start(ready, &format!("Failed to open {} at {}: {}", path, git_tag, e)),
+        };
+        commit_failed = filesys.commit_failed();
+        let mut session = match fuse::Session::new(filesys, &mount_point, &mount_options) {
+            Ok(session) => session,
+            Err(e) => fail_start(ready, &format!("Failed to mount {}: {}", mount_point.display(), e)),
+        };
+        if let Some(ready) = ready {
+            ready.ok();
+        }
+        shutdown::unmount_on_signal(mount_point.clone());
+        if let Err(e) = session.run() {
+            error!("Filesystem session ended with an error: {}", e);
+        }
+    }
+    info!("Shutting down");
+    if let Some(ref pidfile) = pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
+    if commit_failed.load(Ordering::SeqCst) {
+        error!("Final commit failed, changes are left in the journal");
+        process::exit(1);
     }
-    println!("Shutting down!");
 }
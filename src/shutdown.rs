@@ -0,0 +1,83 @@
+use libc;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// How many times unmounting is tried before falling back to a lazy unmount, a busy mount may free up.
+static UNMOUNT_ATTEMPTS: u32 = 5;
+/// Wait between unmount attempts.
+static UNMOUNT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    SIGNALED.store(true, Ordering::SeqCst);
+}
+
+/// Unmounts `mount_point` once SIGINT or SIGTERM is received, which ends the
+/// session loop so the filesystem gets dropped and makes its final commit.
+pub fn unmount_on_signal(mount_point: PathBuf) {
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        while !SIGNALED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!("Received signal, unmounting {}", mount_point.display());
+        for attempt in 1..=UNMOUNT_ATTEMPTS {
+            match unmount(&mount_point) {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "Failed to unmount {} (attempt {}/{}): {}",
+                    mount_point.display(), attempt, UNMOUNT_ATTEMPTS, e
+                ),
+            }
+            thread::sleep(UNMOUNT_RETRY_DELAY);
+        }
+        //Detaches the mount now, the session ends once the last open file is closed
+        match unmount_lazy(&mount_point) {
+            Ok(()) => info!("Lazily unmounted {}", mount_point.display()),
+            Err(e) => {
+                error!("Failed to unmount {}, the next signal ends the process: {}", mount_point.display(), e);
+                unsafe {
+                    libc::signal(libc::SIGINT, libc::SIG_DFL);
+                    libc::signal(libc::SIGTERM, libc::SIG_DFL);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn unmount(mount_point: &Path) -> io::Result<()> {
+    run(Command::new("fusermount").arg("-u").arg(mount_point))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount(mount_point: &Path) -> io::Result<()> {
+    run(Command::new("umount").arg(mount_point))
+}
+
+#[cfg(target_os = "linux")]
+fn unmount_lazy(mount_point: &Path) -> io::Result<()> {
+    run(Command::new("fusermount").arg("-uz").arg(mount_point))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unmount_lazy(mount_point: &Path) -> io::Result<()> {
+    run(Command::new("umount").arg("-f").arg(mount_point))
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let status = command.status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("{}", status)))
+    }
+}